@@ -0,0 +1,123 @@
+// Compares proxying large DataRow messages by reading them into a buffer, the
+// way tusq does by default, with splicing the rest of each message.
+//
+//     cargo bench --bench splice
+
+#[cfg(target_os = "linux")]
+#[path = "../src/splice.rs"]
+mod splice;
+
+#[cfg(target_os = "linux")]
+mod bench {
+    use super::splice::{splice_exact, Pipe};
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const MESSAGE_SIZE: usize = 1024 * 1024;
+    const MESSAGES: usize = 512;
+    const BUFFER_SIZE: usize = 8192;
+
+    #[derive(Clone, Copy)]
+    enum Mode {
+        ReadWrite,
+        Splice,
+    }
+
+    // A connected pair of sockets.
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    // A DataRow with a single column that makes it MESSAGE_SIZE long.
+    fn data_row() -> Vec<u8> {
+        let value_size = MESSAGE_SIZE - 11;
+        let mut msg = vec![b'D'];
+        msg.extend_from_slice(&((MESSAGE_SIZE - 1) as i32).to_be_bytes());
+        msg.extend_from_slice(&1i16.to_be_bytes());
+        msg.extend_from_slice(&(value_size as i32).to_be_bytes());
+        msg.resize(MESSAGE_SIZE, 7);
+        msg
+    }
+
+    async fn run(mode: Mode) -> Duration {
+        let (mut server, proxy_upstream) = socket_pair().await;
+        let (proxy_downstream, mut client) = socket_pair().await;
+        let total = MESSAGE_SIZE * MESSAGES;
+
+        let server = tokio::spawn(async move {
+            let row = data_row();
+            for _ in 0..MESSAGES {
+                server.write_all(&row).await.unwrap();
+            }
+        });
+        let client = tokio::spawn(async move {
+            let mut buffer = vec![0; 64 * 1024];
+            let mut received = 0;
+            while received < total {
+                received += client.read(&mut buffer).await.unwrap();
+            }
+        });
+
+        let start = Instant::now();
+        let mut upstream = proxy_upstream;
+        let mut downstream = proxy_downstream;
+        let mut buffer = vec![0; BUFFER_SIZE];
+        match mode {
+            Mode::ReadWrite => {
+                let mut proxied = 0;
+                while proxied < total {
+                    let n = upstream.read(&mut buffer).await.unwrap();
+                    downstream.write_all(&buffer[..n]).await.unwrap();
+                    proxied += n;
+                }
+            }
+            // Read the first buffer of each message like tusq does, then splice
+            // whatever is left of it.
+            Mode::Splice => {
+                let pipe = Pipe::new().unwrap();
+                for _ in 0..MESSAGES {
+                    upstream.read_exact(&mut buffer).await.unwrap();
+                    downstream.write_all(&buffer).await.unwrap();
+                    splice_exact(&upstream, &downstream, &pipe, MESSAGE_SIZE - BUFFER_SIZE)
+                        .await
+                        .unwrap();
+                }
+            }
+        }
+
+        server.await.unwrap();
+        client.await.unwrap();
+        start.elapsed()
+    }
+
+    pub fn main() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let total_mb = (MESSAGE_SIZE * MESSAGES) as f64 / (1024.0 * 1024.0);
+        for (name, mode) in [("read/write", Mode::ReadWrite), ("splice", Mode::Splice)].iter() {
+            // Warm up once, then take the best of a few runs.
+            runtime.block_on(run(*mode));
+            let best = (0..5).map(|_| runtime.block_on(run(*mode))).min().unwrap();
+            println!(
+                "{:<12} {:>8.1} MiB/s ({:?} for {} MiB)",
+                name,
+                total_mb / best.as_secs_f64(),
+                best,
+                total_mb
+            );
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn main() {
+    bench::main();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    println!("splice(2) is only available on Linux");
+}
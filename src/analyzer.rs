@@ -0,0 +1,319 @@
+// A small, best-effort SQL scanner that spots statements relying on session
+// state. In transaction pooling the next transaction can land on a different
+// server, so these silently misbehave. It does not parse SQL, it only looks at
+// leading keywords of each statement, so false negatives are possible.
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SessionFeature {
+    Listen,
+    // A session level SET of a parameter that isn't tracked by tusq.
+    SessionSet(String),
+    Prepare,
+    // A named prepared statement created with the extended query protocol.
+    NamedStatement(String),
+    AdvisoryLock,
+    TempTable,
+    WithHoldCursor,
+}
+
+impl SessionFeature {
+    pub fn description(&self) -> String {
+        match self {
+            SessionFeature::Listen => "LISTEN".into(),
+            SessionFeature::SessionSet(name) => format!("session level SET of {}", name),
+            SessionFeature::Prepare => "PREPARE".into(),
+            SessionFeature::NamedStatement(name) => format!("named prepared statement {}", name),
+            SessionFeature::AdvisoryLock => "session level advisory lock".into(),
+            SessionFeature::TempTable => "CREATE TEMP TABLE".into(),
+            SessionFeature::WithHoldCursor => "DECLARE ... WITH HOLD cursor".into(),
+        }
+    }
+}
+
+// Check a Parse message. Named statements outlive the transaction, and the query
+// itself might use a session feature too.
+pub fn analyze_parse(
+    statement_name: &str,
+    sql: &str,
+    track_parameters: &[String],
+) -> Option<SessionFeature> {
+    if !statement_name.is_empty() {
+        return Some(SessionFeature::NamedStatement(statement_name.into()));
+    }
+    analyze(sql, track_parameters)
+}
+
+// Check the statements of a simple or extended query. SETs of tracked parameters
+// are fine since tusq replays those on every checkout.
+pub fn analyze(sql: &str, track_parameters: &[String]) -> Option<SessionFeature> {
+    for words in statements(sql) {
+        let words: Vec<&str> = words.iter().map(|word| word.as_str()).collect();
+
+        let feature = match words.as_slice() {
+            ["listen", ..] => Some(SessionFeature::Listen),
+            ["prepare", ..] => Some(SessionFeature::Prepare),
+            ["set", "local", ..] | ["set", "transaction", ..] => None,
+            ["set", "session", "characteristics", ..] => None,
+            ["set", "session", "authorization", ..] | ["set", "role", ..] => {
+                Some(SessionFeature::SessionSet("role".into()))
+            }
+            // A few parameters have a syntax of their own.
+            ["set", "session", "time", "zone", ..] | ["set", "time", "zone", ..] => {
+                session_set("TimeZone", track_parameters)
+            }
+            ["set", "session", "names", ..] | ["set", "names", ..] => {
+                session_set("client_encoding", track_parameters)
+            }
+            ["set", "session", "schema", ..] | ["set", "schema", ..] => {
+                session_set("search_path", track_parameters)
+            }
+            ["set", "session", name, ..] | ["set", name, ..] => {
+                session_set(name.trim_start_matches('"'), track_parameters)
+            }
+            ["create", "temp", ..]
+            | ["create", "temporary", ..]
+            | ["create", "local", "temp", ..]
+            | ["create", "local", "temporary", ..]
+            | ["create", "global", "temp", ..]
+            | ["create", "global", "temporary", ..] => {
+                // Tables dropped at commit don't leak into the session.
+                if words.windows(3).any(|w| w == ["on", "commit", "drop"]) {
+                    None
+                } else {
+                    Some(SessionFeature::TempTable)
+                }
+            }
+            ["declare", ..] if words.windows(2).any(|w| w == ["with", "hold"]) => {
+                Some(SessionFeature::WithHoldCursor)
+            }
+            _ => None,
+        };
+        if feature.is_some() {
+            return feature;
+        }
+
+        // The session level lock functions can show up anywhere in a statement.
+        // The transaction level ones are named pg_advisory_xact_lock, etc.
+        let is_advisory_lock = words.iter().any(|word| {
+            word.starts_with("pg_advisory_lock") || word.starts_with("pg_try_advisory_lock")
+        });
+        if is_advisory_lock {
+            return Some(SessionFeature::AdvisoryLock);
+        }
+    }
+    None
+}
+
+// A SET of `name`, which is fine when tusq tracks the parameter.
+fn session_set(name: &str, track_parameters: &[String]) -> Option<SessionFeature> {
+    let is_tracked = track_parameters
+        .iter()
+        .any(|tracked| tracked.eq_ignore_ascii_case(name));
+    if is_tracked {
+        None
+    } else {
+        Some(SessionFeature::SessionSet(name.to_string()))
+    }
+}
+
+// Split a query string into statements of words. Keywords and identifiers are
+// lowercased. Quoted identifiers keep their text, marked with a leading '"' so
+// they never match a keyword. Comments, string literals and dollar quoted
+// bodies are dropped so they can't cause false positives.
+fn statements(sql: &str) -> Vec<Vec<String>> {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut statements = vec![];
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        // Identifiers may contain a $ after the first character.
+        if c.is_alphanumeric() || c == '_' || (c == '$' && !word.is_empty()) {
+            word.extend(c.to_lowercase());
+            continue;
+        }
+        if !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+
+        match c {
+            '\'' => i = skip_quoted(&chars, i, '\'').1,
+            '"' => {
+                let (identifier, end) = skip_quoted(&chars, i, '"');
+                words.push(format!("\"{}", identifier));
+                i = end;
+            }
+            '$' => {
+                if let Some(tag_end) = dollar_tag(&chars, i) {
+                    // Skip to the same tag, e.g. $$ or $body$.
+                    let tag = &chars[i - 1..tag_end];
+                    i = (tag_end..chars.len())
+                        .find(|&start| chars[start..].starts_with(tag))
+                        .map(|start| start + tag.len())
+                        .unwrap_or(chars.len());
+                }
+            }
+            '-' if chars.get(i) == Some(&'-') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '/' if chars.get(i) == Some(&'*') => {
+                // Past the * of /* so it can't close the comment too.
+                i += 2;
+                while i < chars.len() && !(chars[i - 1] == '*' && chars[i] == '/') {
+                    i += 1;
+                }
+                i += 1;
+            }
+            ';' => statements.push(std::mem::take(&mut words)),
+            _ => {}
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    statements.push(words);
+    statements
+}
+
+// The text between quotes starting at `start`, right after the opening quote,
+// and where it ends. A doubled quote is an escaped quote.
+fn skip_quoted(chars: &[char], start: usize, quote: char) -> (String, usize) {
+    let mut text = String::new();
+    let mut i = start;
+    while i < chars.len() {
+        if chars[i] == quote {
+            if chars.get(i + 1) == Some(&quote) {
+                text.push(quote);
+                i += 2;
+                continue;
+            }
+            return (text, i + 1);
+        }
+        text.push(chars[i]);
+        i += 1;
+    }
+    (text, i)
+}
+
+// Where the tag of a dollar quote ends when the $ before `start` opens one. A $
+// followed by a digit is a parameter like $1 instead.
+fn dollar_tag(chars: &[char], start: usize) -> Option<usize> {
+    if chars.get(start).is_some_and(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let mut i = start;
+    while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+        i += 1;
+    }
+    match chars.get(i) {
+        Some('$') => Some(i + 1),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracked() -> Vec<String> {
+        vec![
+            "TimeZone".into(),
+            "application_name".into(),
+            "client_encoding".into(),
+        ]
+    }
+
+    #[test]
+    fn it_detects_session_features() {
+        let cases = vec![
+            ("LISTEN events", SessionFeature::Listen),
+            ("prepare q as select 1", SessionFeature::Prepare),
+            (
+                "SET search_path = app",
+                SessionFeature::SessionSet("search_path".into()),
+            ),
+            (
+                "set session statement_timeout to 5",
+                SessionFeature::SessionSet("statement_timeout".into()),
+            ),
+            ("SET ROLE admin", SessionFeature::SessionSet("role".into())),
+            ("select pg_advisory_lock(1)", SessionFeature::AdvisoryLock),
+            (
+                "SELECT pg_try_advisory_lock_shared(1, 2)",
+                SessionFeature::AdvisoryLock,
+            ),
+            ("CREATE TEMP TABLE t (id int)", SessionFeature::TempTable),
+            (
+                "create global temporary table t (id int)",
+                SessionFeature::TempTable,
+            ),
+            (
+                "DECLARE c CURSOR WITH HOLD FOR SELECT 1",
+                SessionFeature::WithHoldCursor,
+            ),
+            ("select 1; listen events", SessionFeature::Listen),
+            (
+                "SET \"search_path\" TO app",
+                SessionFeature::SessionSet("search_path".into()),
+            ),
+            (
+                "SET SCHEMA 'app'",
+                SessionFeature::SessionSet("search_path".into()),
+            ),
+            ("select $$;$$; listen events", SessionFeature::Listen),
+            (
+                "select $1::int; select pg_advisory_lock($2)",
+                SessionFeature::AdvisoryLock,
+            ),
+        ];
+
+        for (sql, expected) in cases {
+            assert_eq!(analyze(sql, &tracked()), Some(expected), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn it_ignores_transaction_scoped_statements() {
+        let cases = vec![
+            "select 1",
+            "SET LOCAL statement_timeout = 5",
+            "SET TRANSACTION ISOLATION LEVEL SERIALIZABLE",
+            "SET TimeZone = 'UTC'",
+            "set application_name to 'app'",
+            "select pg_advisory_xact_lock(1)",
+            "CREATE TEMP TABLE t (id int) ON COMMIT DROP",
+            "DECLARE c CURSOR FOR SELECT 1",
+            "select 'listen' -- listen\n",
+            "/* prepare */ select \"pg_advisory_lock\" from t",
+            "SET TIME ZONE 'UTC'",
+            "set session time zone local",
+            "SET \"TimeZone\" TO 'UTC'",
+            "SET NAMES 'UTF8'",
+            "CREATE FUNCTION f() RETURNS void AS $$ BEGIN SET search_path = x; LISTEN y; END $$ LANGUAGE plpgsql",
+            "DO $body$ BEGIN PERFORM pg_advisory_lock(1); END $body$",
+            "select $a$ it's $$ ; listen x $a$",
+        ];
+
+        for sql in cases {
+            assert_eq!(analyze(sql, &tracked()), None, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn it_detects_named_statements() {
+        assert_eq!(
+            analyze_parse("s1", "select 1", &tracked()),
+            Some(SessionFeature::NamedStatement("s1".into()))
+        );
+        assert_eq!(analyze_parse("", "select 1", &tracked()), None);
+        assert_eq!(
+            analyze_parse("", "listen events", &tracked()),
+            Some(SessionFeature::Listen)
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+// CircuitBreaker tracks consecutive connect failures for a single database.
+// Once `threshold` failures happen in a row the breaker opens and callers are
+// expected to fail fast instead of queueing up on the pool. After `cooldown`
+// has passed a single caller is allowed through to probe the server. A
+// successful probe closes the breaker, a failed one re-opens it.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+    opened: Notify,
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    // Taken from the config on every connect, so a reload applies right away.
+    threshold: u32,
+    cooldown: Duration,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    is_probing: bool,
+}
+
+impl CircuitBreaker {
+    // A threshold of 0 disables the breaker entirely.
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            state: Mutex::new(BreakerState {
+                threshold,
+                cooldown,
+                consecutive_failures: 0,
+                opened_at: None,
+                is_probing: false,
+            }),
+            opened: Notify::new(),
+        }
+    }
+
+    // Apply the current settings. Disabling the breaker also closes it.
+    pub fn configure(&self, threshold: u32, cooldown: Duration) {
+        let mut state = self.state.lock().expect("breaker lock");
+        state.threshold = threshold;
+        state.cooldown = cooldown;
+        if threshold == 0 {
+            state.consecutive_failures = 0;
+            state.opened_at = None;
+            state.is_probing = false;
+        }
+    }
+
+    // Returns true when the breaker is open and the cooldown has not passed yet.
+    // This does not claim the half-open probe, so it is safe to call from
+    // clients that only want to know whether to fail fast.
+    pub fn is_open(&self) -> bool {
+        let state = self.state.lock().expect("breaker lock");
+        match state.opened_at {
+            Some(opened_at) => opened_at.elapsed() < state.cooldown || state.is_probing,
+            None => false,
+        }
+    }
+
+    // Resolves once the breaker is open. Clients already waiting on the pool use
+    // this to bail out instead of waiting for the pool checkout to time out.
+    pub async fn wait_open(&self) {
+        loop {
+            let opened = self.opened.notified();
+            if self.is_open() {
+                return;
+            }
+            opened.await;
+        }
+    }
+
+    // Ask permission to open a new server connection. While open, only a single
+    // probe is allowed through once the cooldown has elapsed.
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().expect("breaker lock");
+        match state.opened_at {
+            None => true,
+            Some(opened_at) => {
+                if state.is_probing || opened_at.elapsed() < state.cooldown {
+                    return false;
+                }
+                state.is_probing = true;
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().expect("breaker lock");
+        if state.opened_at.is_some() {
+            log::warn!("Circuit breaker closed after a successful connect.");
+        }
+        state.consecutive_failures = 0;
+        state.opened_at = None;
+        state.is_probing = false;
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().expect("breaker lock");
+        if state.threshold == 0 {
+            return;
+        }
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+
+        // A failed probe re-opens the breaker for another cooldown period.
+        if state.is_probing || state.consecutive_failures >= state.threshold {
+            if state.opened_at.is_none() {
+                log::warn!(
+                    "Circuit breaker opened after {} consecutive connect failures.",
+                    state.consecutive_failures
+                );
+            }
+            state.opened_at = Some(Instant::now());
+            state.is_probing = false;
+            self.opened.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_opens_after_threshold_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        assert!(breaker.try_acquire());
+
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+        assert!(breaker.try_acquire());
+
+        breaker.record_failure();
+        assert!(breaker.is_open());
+        assert!(!breaker.try_acquire());
+    }
+
+    #[test]
+    fn it_allows_a_single_probe_after_cooldown() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(0));
+        breaker.record_failure();
+
+        // Only the first caller gets to probe.
+        assert!(breaker.try_acquire());
+        assert!(breaker.is_open());
+        assert!(!breaker.try_acquire());
+
+        // A failed probe re-opens the breaker.
+        breaker.record_failure();
+        assert!(breaker.try_acquire());
+
+        // A successful probe closes it.
+        breaker.record_success();
+        assert!(!breaker.is_open());
+        assert!(breaker.try_acquire());
+        assert!(breaker.try_acquire());
+    }
+
+    #[test]
+    fn it_never_opens_when_disabled() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            breaker.record_failure();
+        }
+        assert!(!breaker.is_open());
+        assert!(breaker.try_acquire());
+    }
+
+    #[test]
+    fn it_picks_up_new_settings() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(60));
+        breaker.configure(1, Duration::from_secs(60));
+        breaker.record_failure();
+        assert!(breaker.is_open());
+
+        // Turning it off lets clients through right away.
+        breaker.configure(0, Duration::from_secs(60));
+        assert!(!breaker.is_open());
+        assert!(breaker.try_acquire());
+    }
+}
@@ -0,0 +1,110 @@
+use bytes::BytesMut;
+use std::sync::Mutex;
+
+// Buffers kept around for reuse. Anything past this is freed.
+const MAX_POOLED_BUFFERS: usize = 1024;
+
+// How large connection buffers start out and how far they may grow.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferSize {
+    pub initial: usize,
+    pub max: usize,
+}
+
+impl BufferSize {
+    // The next size for a buffer that was filled by a single read. Buffers
+    // double until they reach the max.
+    pub fn grow(&self, current: usize) -> Option<usize> {
+        if current >= self.max {
+            return None;
+        }
+        Some(std::cmp::min(current * 2, self.max))
+    }
+}
+
+// Connection buffers are recycled through a process wide pool. Idle clients
+// give theirs back, so thousands of them don't each hold on to one.
+pub struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+}
+
+pub static BUFFERS: BufferPool = BufferPool {
+    buffers: Mutex::new(Vec::new()),
+};
+
+impl BufferPool {
+    // A zeroed buffer of `size` bytes, reused when one is available.
+    pub fn take(&self, size: usize) -> BytesMut {
+        let buffer = self.buffers.lock().expect("buffer pool lock").pop();
+        let mut buffer = match buffer {
+            // Sizes change with a config reload, so smaller buffers are dropped.
+            Some(buffer) if buffer.capacity() >= size => buffer,
+            _ => BytesMut::with_capacity(size),
+        };
+        buffer.clear();
+        buffer.resize(size, 0);
+        buffer
+    }
+
+    // Return a buffer to the pool. Buffers that grew for a bulk result are
+    // freed instead, so the pool doesn't keep large ones alive.
+    pub fn give(&self, buffer: BytesMut, size: BufferSize) {
+        if buffer.capacity() == 0 || buffer.capacity() > size.initial {
+            return;
+        }
+        let mut buffers = self.buffers.lock().expect("buffer pool lock");
+        if buffers.len() < MAX_POOLED_BUFFERS {
+            buffers.push(buffer);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffers.lock().expect("buffer pool lock").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_grows_buffers_up_to_the_max() {
+        let size = BufferSize {
+            initial: 8192,
+            max: 20000,
+        };
+        assert_eq!(size.grow(8192), Some(16384));
+        assert_eq!(size.grow(16384), Some(20000));
+        assert_eq!(size.grow(20000), None);
+    }
+
+    #[test]
+    fn it_reuses_buffers() {
+        let pool = BufferPool {
+            buffers: Mutex::new(Vec::new()),
+        };
+        let size = BufferSize {
+            initial: 1024,
+            max: 4096,
+        };
+
+        let mut buffer = pool.take(1024);
+        assert_eq!(buffer.len(), 1024);
+        buffer[0] = 1;
+        pool.give(buffer, size);
+        assert_eq!(pool.len(), 1);
+
+        // Buffers come back zeroed.
+        let buffer = pool.take(1024);
+        assert_eq!(buffer[0], 0);
+        assert!(pool.is_empty());
+
+        // Grown buffers are not kept.
+        pool.give(BytesMut::with_capacity(4096), size);
+        assert!(pool.is_empty());
+    }
+}
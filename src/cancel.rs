@@ -0,0 +1,171 @@
+// Query cancellation. Every client gets its own backend key from tusq, so a
+// CancelRequest names a client rather than a server. While the client has a
+// server checked out, the request is passed on to that server with the key the
+// server handed tusq, like pgbouncer does.
+
+use crate::proto::messages;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+// How long to try reaching the server with a cancel request.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The clients of this process by process id.
+static CLIENTS: Mutex<BTreeMap<i32, Client>> = Mutex::new(BTreeMap::new());
+
+struct Client {
+    secret_key: Vec<u8>,
+    // The server the client has checked out, if any.
+    target: Option<Target>,
+}
+
+// Where a cancel request for a client goes: its server, with the server's key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub addr: SocketAddr,
+    pub process_id: i32,
+    pub secret_key: Vec<u8>,
+}
+
+// A client that can be canceled. It is forgotten once this is dropped.
+#[derive(Debug)]
+pub struct Registration {
+    process_id: i32,
+}
+
+impl Registration {
+    // Returns None when another client already has the process id.
+    pub fn new(process_id: i32, secret_key: Vec<u8>) -> Option<Self> {
+        let mut clients = CLIENTS.lock().expect("cancel lock");
+        if clients.contains_key(&process_id) {
+            return None;
+        }
+        let client = Client {
+            secret_key,
+            target: None,
+        };
+        clients.insert(process_id, client);
+        Some(Self { process_id })
+    }
+
+    // Send cancel requests to `target` until the returned guard is dropped.
+    pub fn checkout(&self, target: Target) -> Checkout {
+        set_target(self.process_id, Some(target));
+        Checkout {
+            process_id: self.process_id,
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        CLIENTS
+            .lock()
+            .expect("cancel lock")
+            .remove(&self.process_id);
+    }
+}
+
+// A client's server, for as long as it has it checked out. Drop this before the
+// server goes back to the pool, so a late cancel can't hit the next client.
+pub struct Checkout {
+    process_id: i32,
+}
+
+impl Drop for Checkout {
+    fn drop(&mut self) {
+        set_target(self.process_id, None);
+    }
+}
+
+fn set_target(process_id: i32, target: Option<Target>) {
+    if let Some(client) = CLIENTS.lock().expect("cancel lock").get_mut(&process_id) {
+        client.target = target;
+    }
+}
+
+// Compare secrets without giving away through timing how much of one matched.
+fn is_same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Where a cancel request with this key goes. None when the client is between
+// transactions, so there is nothing to cancel.
+fn target(process_id: i32, secret_key: &[u8]) -> anyhow::Result<Option<Target>> {
+    let clients = CLIENTS.lock().expect("cancel lock");
+    match clients.get(&process_id) {
+        Some(client) if is_same_secret(&client.secret_key, secret_key) => Ok(client.target.clone()),
+        _ => anyhow::bail!(
+            "Cancel request with an unknown key for process {}",
+            process_id
+        ),
+    }
+}
+
+// Handle a CancelRequest from a client.
+pub async fn cancel(process_id: i32, secret_key: &[u8]) -> anyhow::Result<()> {
+    let target = match target(process_id, secret_key)? {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    log::info!(
+        "Canceling the query of process {} on server {}",
+        process_id,
+        target.addr
+    );
+
+    let msg = messages::cancel_request(target.process_id, &target.secret_key);
+    let send = async {
+        let mut conn = TcpStream::connect(target.addr).await?;
+        conn.write_all(&msg).await?;
+        anyhow::Ok(())
+    };
+    match tokio::time::timeout(CANCEL_TIMEOUT, send).await {
+        Ok(res) => res,
+        Err(_) => anyhow::bail!("Timed out sending a cancel request to {}", target.addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn it_compares_secrets() {
+        assert!(is_same_secret(&[1, 2, 3, 4], &[1, 2, 3, 4]));
+        assert!(!is_same_secret(&[1, 2, 3, 4], &[1, 2, 3, 5]));
+        assert!(!is_same_secret(&[1, 2, 3, 4], &[1, 2, 3]));
+    }
+
+    #[tokio::test]
+    async fn it_forwards_a_cancel_to_the_checked_out_server() {
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let registration = Registration::new(-100, vec![7; 32]).unwrap();
+        assert!(Registration::new(-100, vec![1; 32]).is_none());
+
+        // Nothing to cancel between transactions, and a wrong key is refused.
+        assert!(cancel(-100, &[7; 32]).await.is_ok());
+        assert!(cancel(-100, &[8; 32]).await.is_err());
+
+        let checkout = registration.checkout(Target {
+            addr: server.local_addr().unwrap(),
+            process_id: 1234,
+            secret_key: vec![1, 2, 3, 4],
+        });
+        cancel(-100, &[7; 32]).await.unwrap();
+        let (mut conn, _) = server.accept().await.unwrap();
+        let mut msg = vec![];
+        conn.read_to_end(&mut msg).await.unwrap();
+        assert_eq!(msg, messages::cancel_request(1234, &[1, 2, 3, 4]));
+
+        drop(checkout);
+        assert_eq!(target(-100, &[7; 32]).unwrap(), None);
+        drop(registration);
+        assert!(target(-100, &[7; 32]).is_err());
+    }
+}
@@ -1,11 +1,19 @@
+use crate::buffer::BufferSize;
 use serde::Deserialize;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use tokio::sync::{RwLock, RwLockReadGuard};
 
+// Buffers need room for more than a message header.
+const MIN_PKT_BUF: usize = 1024;
+
+// The databases entry used as a template for names that aren't configured.
+pub const AUTO_DATABASE: &str = "*";
+
 #[derive(Debug, Clone)]
 pub struct UpdatableConfig {
     inner: Arc<RwLock<Config>>,
@@ -30,9 +38,72 @@ impl UpdatableConfig {
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
-    pub bind_address: String,
+    // Listeners are bound once at startup. Changes require a restart.
+    pub listeners: Vec<Listener>,
+
+    // Socket options applied to both client and server connections.
+    #[serde(default)]
+    pub tcp: TcpOptions,
+
+    // Databases by the name clients connect with. A "*" entry is a template for
+    // any other name: pools for those are created on demand.
     pub databases: BTreeMap<String, Database>,
 
+    // Pools created from the "*" template are dropped once they have had no
+    // clients for this long.
+    #[serde(default = "default_auto_database_idle_timeout_ms")]
+    pub auto_database_idle_timeout_ms: u64,
+
+    // Session parameters a client may set in its startup packet that are
+    // replayed with SET whenever the client checks out a server connection.
+    #[serde(default = "default_track_parameters")]
+    pub track_parameters: Vec<String>,
+
+    // Startup parameters that are accepted and dropped. Any other parameter that
+    // isn't tracked is refused, since it would silently not apply. JDBC always
+    // sends extra_float_digits, so it is ignored by default.
+    #[serde(default = "default_ignore_startup_parameters")]
+    pub ignore_startup_parameters: Vec<String>,
+
+    // How application_name is set on server connections.
+    #[serde(default)]
+    pub application_name: ApplicationName,
+
+    // What to do with statements that depend on session state, like LISTEN or a
+    // session level SET. These don't work reliably with transaction pooling.
+    #[serde(default)]
+    pub session_features: SessionFeatureMode,
+
+    // The size of each connection's read buffer. A buffer that is filled by a
+    // single read doubles, up to max_pkt_buf, and shrinks back once the
+    // connection is idle again.
+    #[serde(default = "default_pkt_buf")]
+    pub pkt_buf: usize,
+    #[serde(default = "default_max_pkt_buf")]
+    pub max_pkt_buf: usize,
+
+    // Linux only: the rest of a DataRow or CopyData message from a server that
+    // has at least splice_threshold bytes to go is moved to the client with
+    // splice(2) instead of being read by tusq.
+    #[serde(default)]
+    pub splice: bool,
+    #[serde(default = "default_splice_threshold")]
+    pub splice_threshold: usize,
+
+    // A Unix socket a newer tusq process (started with --upgrade) connects to
+    // for this process's listening sockets. This process then stops accepting
+    // clients and exits once its clients are done.
+    pub upgrade_socket: Option<String>,
+
+    // How long a shutdown waits for clients to finish their transactions before
+    // closing them anyway. Set to 0 to wait for as long as it takes.
+    #[serde(default = "default_shutdown_timeout_ms")]
+    pub shutdown_timeout_ms: u64,
+
+    // How often stats are logged. Set to 0 to turn stats logging off.
+    #[serde(default = "default_stats_period_ms")]
+    pub stats_period_ms: u64,
+
     #[serde(default = "SystemTime::now")]
     pub updated_at: SystemTime,
 }
@@ -42,9 +113,59 @@ impl Config {
         let mut contents = vec![];
         file.read_to_end(&mut contents).await?;
         let config: Config = toml::from_slice(&contents)?;
+
+        if config.pkt_buf < MIN_PKT_BUF {
+            anyhow::bail!("pkt_buf must be at least {} bytes", MIN_PKT_BUF);
+        }
+        if config.max_pkt_buf < config.pkt_buf {
+            anyhow::bail!("max_pkt_buf must not be smaller than pkt_buf");
+        }
         Ok(config)
     }
 
+    pub fn shutdown_timeout(&self) -> Option<Duration> {
+        match self.shutdown_timeout_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    // The splice threshold when splicing is on and supported.
+    pub fn splice_threshold(&self) -> Option<usize> {
+        if self.splice && cfg!(target_os = "linux") {
+            Some(self.splice_threshold)
+        } else {
+            None
+        }
+    }
+
+    pub fn buffer_size(&self) -> BufferSize {
+        BufferSize {
+            initial: self.pkt_buf,
+            max: self.max_pkt_buf,
+        }
+    }
+
+    // Look up the options for a database, falling back to the "*" template. A
+    // templated database connects to the server database of the same name.
+    pub fn database(&self, name: &str) -> Option<Database> {
+        if let Some(database) = self.databases.get(name) {
+            let mut database = database.clone();
+            if database.dbname.is_empty() {
+                database.dbname = name.into();
+            }
+            return Some(database);
+        }
+
+        let mut database = self.databases.get(AUTO_DATABASE)?.clone();
+        database.dbname = name.into();
+        Some(database)
+    }
+
+    pub fn is_auto_database(&self, name: &str) -> bool {
+        !self.databases.contains_key(name) && self.databases.contains_key(AUTO_DATABASE)
+    }
+
     pub fn example() -> Self {
         // Create a map with required database options.
         let db = Database {
@@ -54,6 +175,12 @@ impl Config {
             user: "testuser".into(),
             password: Some("123456".into()),
             pool_size: 25,
+            connect_timeout_ms: default_connect_timeout_ms(),
+            connect_retries: default_connect_retries(),
+            connect_backoff_ms: default_connect_backoff_ms(),
+            connect_backoff_max_ms: default_connect_backoff_max_ms(),
+            breaker_threshold: default_breaker_threshold(),
+            breaker_cooldown_ms: default_breaker_cooldown_ms(),
         };
 
         // Use above options to create an aliased database.
@@ -62,22 +189,242 @@ impl Config {
 
         Self {
             updated_at: SystemTime::now(),
-            bind_address: "localhost:8432".into(),
+            listeners: vec![Listener {
+                address: "localhost:8432".into(),
+                proxy_protocol: false,
+                reuse_port: false,
+                databases: None,
+            }],
+            tcp: TcpOptions::default(),
             databases,
+            track_parameters: default_track_parameters(),
+            ignore_startup_parameters: default_ignore_startup_parameters(),
+            application_name: ApplicationName::default(),
+            auto_database_idle_timeout_ms: default_auto_database_idle_timeout_ms(),
+            session_features: SessionFeatureMode::default(),
+            pkt_buf: default_pkt_buf(),
+            max_pkt_buf: default_max_pkt_buf(),
+            splice: false,
+            splice_threshold: default_splice_threshold(),
+            upgrade_socket: None,
+            shutdown_timeout_ms: default_shutdown_timeout_ms(),
+            stats_period_ms: default_stats_period_ms(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Listener {
+    pub address: String,
+
+    // Expect a PROXY protocol (v1 or v2) header on every client connection.
+    // Only enable this when tusq is behind a load balancer that sends one.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+
+    // Bind with SO_REUSEPORT, so another tusq process can listen on the same
+    // address at the same time, e.g. while upgrading.
+    #[serde(default)]
+    pub reuse_port: bool,
+
+    // Databases clients of this listener may connect to. All databases are
+    // exposed when this is not set.
+    pub databases: Option<BTreeSet<String>>,
+}
+
+impl Listener {
+    pub fn allows_database(&self, dbname: &str) -> bool {
+        match self.databases {
+            Some(ref databases) => databases.contains(dbname),
+            None => true,
+        }
+    }
+}
+
+// Anything left unset keeps the operating system default.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct TcpOptions {
+    // Enable TCP keepalives. NATs and firewalls tend to drop idle connections
+    // without telling either side, so this is worth turning on.
+    #[serde(default)]
+    pub keepalive: bool,
+
+    // Idle time before the first keepalive probe is sent.
+    pub keepalive_idle_ms: Option<u64>,
+
+    // Time between keepalive probes.
+    pub keepalive_interval_ms: Option<u64>,
+
+    // Number of unanswered probes before the connection is considered dead.
+    pub keepalive_count: Option<u32>,
+
+    // TCP_USER_TIMEOUT: max time transmitted data may remain unacknowledged
+    // before the connection is closed. Linux only.
+    pub user_timeout_ms: Option<u64>,
+
+    // SO_RCVBUF and SO_SNDBUF sizes in bytes.
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ApplicationName {
+    // Every server connection uses the same application_name.
+    Static {
+        #[serde(default = "default_application_name")]
+        value: String,
+    },
+    // The client's application_name is set on checkout, optionally with a prefix
+    // and the client address appended, e.g. "tusq/psql - 10.0.0.7:53122".
+    Forward {
+        #[serde(default)]
+        prefix: String,
+        #[serde(default)]
+        add_client_addr: bool,
+    },
+}
+
+impl Default for ApplicationName {
+    fn default() -> Self {
+        ApplicationName::Forward {
+            prefix: "".into(),
+            add_client_addr: false,
         }
     }
 }
 
+impl ApplicationName {
+    // The application_name sent in the startup packet of new server connections.
+    pub fn connect_value(&self) -> String {
+        match self {
+            ApplicationName::Static { value } => value.clone(),
+            ApplicationName::Forward { .. } => default_application_name(),
+        }
+    }
+
+    // The application_name a client's server connection should have, or `None`
+    // to leave it to `track_parameters`.
+    pub fn server_value(
+        &self,
+        client_value: Option<&str>,
+        client_addr: Option<SocketAddr>,
+    ) -> Option<String> {
+        match self {
+            ApplicationName::Static { value } => Some(value.clone()),
+            ApplicationName::Forward {
+                prefix,
+                add_client_addr,
+            } => {
+                if prefix.is_empty() && !add_client_addr {
+                    return None;
+                }
+
+                let mut value = format!("{}{}", prefix, client_value.unwrap_or_default());
+                if let (true, Some(client_addr)) = (add_client_addr, client_addr) {
+                    value.push_str(&format!(" - {}", client_addr));
+                }
+                Some(value)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionFeatureMode {
+    // Don't look at client statements at all.
+    #[default]
+    Off,
+    // Count and log a warning, but let the statement through.
+    Warn,
+    // Count and answer with an error instead of running the statement.
+    Reject,
+    // Count and keep the client on its current server for the rest of its
+    // session. That client falls back to session pooling, everyone else is
+    // unaffected.
+    Pin,
+}
+
+const fn default_auto_database_idle_timeout_ms() -> u64 {
+    3_600_000
+}
+
+fn default_application_name() -> String {
+    "tusq".to_string()
+}
+
+fn default_track_parameters() -> Vec<String> {
+    vec![
+        "application_name".into(),
+        "client_encoding".into(),
+        "DateStyle".into(),
+        "IntervalStyle".into(),
+        "standard_conforming_strings".into(),
+        "TimeZone".into(),
+    ]
+}
+
+fn default_ignore_startup_parameters() -> Vec<String> {
+    vec!["extra_float_digits".into()]
+}
+
 fn default_port() -> String {
     "5432".to_string()
 }
 
+const fn default_pkt_buf() -> usize {
+    8192
+}
+
+const fn default_max_pkt_buf() -> usize {
+    256 * 1024
+}
+
+const fn default_splice_threshold() -> usize {
+    64 * 1024
+}
+
+const fn default_shutdown_timeout_ms() -> u64 {
+    30_000
+}
+
+const fn default_stats_period_ms() -> u64 {
+    60_000
+}
+
 const fn default_pool_size() -> u32 {
     25
 }
 
+const fn default_connect_timeout_ms() -> u64 {
+    5_000
+}
+
+const fn default_connect_retries() -> u32 {
+    2
+}
+
+const fn default_connect_backoff_ms() -> u64 {
+    100
+}
+
+const fn default_connect_backoff_max_ms() -> u64 {
+    2_000
+}
+
+const fn default_breaker_threshold() -> u32 {
+    5
+}
+
+const fn default_breaker_cooldown_ms() -> u64 {
+    10_000
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Database {
+    // The database name on the server. Defaults to the name clients use.
+    #[serde(default)]
     pub dbname: String,
     pub user: String,
     pub host: String,
@@ -88,6 +435,29 @@ pub struct Database {
 
     #[serde(default = "default_pool_size")]
     pub pool_size: u32,
+
+    // Max time to establish a server connection, including the auth handshake.
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+
+    // Extra connect attempts after the first one fails. The delay between
+    // attempts starts at `connect_backoff_ms` and doubles up to the max.
+    #[serde(default = "default_connect_retries")]
+    pub connect_retries: u32,
+
+    #[serde(default = "default_connect_backoff_ms")]
+    pub connect_backoff_ms: u64,
+
+    #[serde(default = "default_connect_backoff_max_ms")]
+    pub connect_backoff_max_ms: u64,
+
+    // Consecutive connect failures before clients start failing fast. Set to 0
+    // to disable the circuit breaker.
+    #[serde(default = "default_breaker_threshold")]
+    pub breaker_threshold: u32,
+
+    #[serde(default = "default_breaker_cooldown_ms")]
+    pub breaker_cooldown_ms: u64,
 }
 
 impl Database {
@@ -98,3 +468,38 @@ impl Database {
         params
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_falls_back_to_the_auto_database() {
+        let mut config = Config::example();
+        assert!(config.database("other_db").is_none());
+        assert!(!config.is_auto_database("other_db"));
+
+        let template = config.databases["my_db_alias"].clone();
+        config.databases.insert(AUTO_DATABASE.into(), template);
+
+        let database = config.database("other_db").unwrap();
+        assert_eq!(database.dbname, "other_db");
+        assert!(config.is_auto_database("other_db"));
+
+        // Configured databases still win.
+        let database = config.database("my_db_alias").unwrap();
+        assert_eq!(database.dbname, "dispatch_development");
+        assert!(!config.is_auto_database("my_db_alias"));
+    }
+
+    #[test]
+    fn it_ignores_extra_float_digits_by_default() {
+        let config: Config = toml::from_str("listeners = []\n[databases]\n").unwrap();
+        assert_eq!(config.ignore_startup_parameters, vec!["extra_float_digits"]);
+
+        let config: Config =
+            toml::from_str("listeners = []\nignore_startup_parameters = []\n[databases]\n")
+                .unwrap();
+        assert!(config.ignore_startup_parameters.is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+use crate::proto::messages;
+
+// Where a server connection is within a COPY. The server switches into one of
+// these with CopyInResponse ('G'), CopyOutResponse ('H') or CopyBothResponse
+// ('W') and CopyData flows until a side sends CopyDone or CopyFail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CopyState {
+    #[default]
+    Idle,
+    // COPY FROM STDIN: the client sends rows.
+    In,
+    // COPY TO STDOUT: the server sends rows.
+    Out,
+    // Both sides send data, as in streaming replication.
+    Both,
+}
+
+// Follows the messages of one transaction to track the COPY sub-state. Client
+// messages go through `client_msg` and server messages through `server_msg`.
+#[derive(Debug, Default)]
+pub struct CopyTracker {
+    pub state: CopyState,
+    // Whether the last command was an extended query Execute rather than a
+    // simple Query. The server needs a Sync to recover from an aborted COPY
+    // started by an Execute.
+    extended: bool,
+    // The same, for the COPY that is running.
+    copy_extended: bool,
+}
+
+impl CopyTracker {
+    pub fn client_msg(&mut self, msg_type: char) {
+        match (msg_type, self.state) {
+            ('Q', _) => self.extended = false,
+            ('E', _) => self.extended = true,
+            ('c', CopyState::In) | ('f', CopyState::In) => self.state = CopyState::Idle,
+            ('c', CopyState::Both) => self.state = CopyState::Out,
+            _ => {}
+        }
+    }
+
+    pub fn server_msg(&mut self, msg_type: char) {
+        match (msg_type, self.state) {
+            ('G', _) => self.start(CopyState::In),
+            ('H', _) => self.start(CopyState::Out),
+            ('W', _) => self.start(CopyState::Both),
+            ('c', CopyState::Out) => self.state = CopyState::Idle,
+            ('c', CopyState::Both) => self.state = CopyState::In,
+            // An error or the end of the command leaves copy mode either way.
+            ('E', _) | ('C', _) | ('Z', _) => self.state = CopyState::Idle,
+            _ => {}
+        }
+    }
+
+    fn start(&mut self, state: CopyState) {
+        self.state = state;
+        self.copy_extended = self.extended;
+    }
+
+    // The server is still waiting on rows from the client.
+    pub fn is_waiting_for_client(&self) -> bool {
+        matches!(self.state, CopyState::In | CopyState::Both)
+    }
+
+    // What to send the server to end a COPY the client walked away from. The
+    // server answers with an ErrorResponse and a single ReadyForQuery.
+    pub fn abort_messages(&self, reason: &str) -> Vec<u8> {
+        let mut msgs = messages::copy_fail(reason);
+        if self.copy_extended {
+            msgs.extend_from_slice(&messages::sync());
+        }
+        msgs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_tracks_copy_from_stdin() {
+        let mut copy = CopyTracker::default();
+        copy.client_msg('Q');
+        copy.server_msg('G');
+        assert_eq!(copy.state, CopyState::In);
+        assert!(copy.is_waiting_for_client());
+        assert_eq!(copy.abort_messages("bye"), messages::copy_fail("bye"));
+
+        copy.client_msg('d');
+        copy.client_msg('c');
+        assert_eq!(copy.state, CopyState::Idle);
+        copy.server_msg('C');
+        copy.server_msg('Z');
+        assert_eq!(copy.state, CopyState::Idle);
+
+        // A server error ends it early.
+        copy.server_msg('G');
+        copy.server_msg('E');
+        assert!(!copy.is_waiting_for_client());
+    }
+
+    #[test]
+    fn it_tracks_copy_to_stdout() {
+        let mut copy = CopyTracker::default();
+        copy.client_msg('Q');
+        copy.server_msg('H');
+        assert_eq!(copy.state, CopyState::Out);
+        assert!(!copy.is_waiting_for_client());
+
+        copy.server_msg('d');
+        copy.server_msg('c');
+        assert_eq!(copy.state, CopyState::Idle);
+    }
+
+    #[test]
+    fn it_tracks_copy_both() {
+        let mut copy = CopyTracker::default();
+        copy.server_msg('W');
+        copy.client_msg('c');
+        assert_eq!(copy.state, CopyState::Out);
+        copy.server_msg('c');
+        assert_eq!(copy.state, CopyState::Idle);
+
+        copy.server_msg('W');
+        copy.server_msg('c');
+        assert_eq!(copy.state, CopyState::In);
+        assert!(copy.is_waiting_for_client());
+    }
+
+    #[test]
+    fn it_syncs_after_aborting_an_extended_copy() {
+        let mut copy = CopyTracker::default();
+        for msg_type in ['P', 'B', 'D', 'E', 'S'].iter() {
+            copy.client_msg(*msg_type);
+        }
+        copy.server_msg('1');
+        copy.server_msg('2');
+        copy.server_msg('G');
+
+        let mut expected = messages::copy_fail("bye");
+        expected.extend_from_slice(&messages::sync());
+        assert_eq!(copy.abort_messages("bye"), expected);
+    }
+}
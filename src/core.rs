@@ -1,17 +1,59 @@
-use crate::pool::{PgConnPool, PgPooler};
+use crate::analyzer::{self, SessionFeature};
+use crate::buffer::{BufferSize, BUFFERS};
+use crate::cancel;
+use crate::config::{Listener, SessionFeatureMode, UpdatableConfig};
+use crate::copy::CopyTracker;
+use crate::error::{sqlstate, PgError};
+use crate::pipeline::Pipeline;
+use crate::pool::{PgConnPool, PgPooler, ServerPool};
+use crate::proto::views::{Assembler, Frontend};
 use crate::proto::{messages, ProtoMessage, ProtoParser, ProtoStartup, StartupMessage};
+use crate::proxy;
+use crate::stats::{GaugeGuard, Stats, STATS};
+use crate::upgrade::{self, ClientState, Migration, Migrations};
+use bb8::PooledConnection;
 use bytes::BytesMut;
 use futures::future::select;
 use futures::future::Either;
-use net::write_all_with_timeout;
+use net::{write_all_with_timeout, AsTcpStream, Readable};
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
 use std::collections::{BTreeMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
 use std::time::SystemTime;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::sync::{oneshot, watch};
+
+const APPLICATION_NAME: &str = "application_name";
+
+// Queries larger than this are not looked at by the session feature analyzer.
+const MAX_ANALYZED_MESSAGE_SIZE: usize = 1024 * 1024;
+
+// The shutdown value sent once `shutdown_timeout_ms` has passed. Clients still in
+// a transaction are closed.
+pub const FORCE_SHUTDOWN: &str = "force shutdown";
+
+// How a client message from the last read goes to the server, when the analyzer
+// is on. A Query or Parse split across reads is held back until it is complete,
+// so one that gets rejected never reaches the server.
+enum Forward {
+    // As read.
+    Buffer,
+    // A piece of a message that is still coming in.
+    Held,
+    // The last piece of a held message, with the whole body.
+    Assembled(Vec<u8>),
+}
 
 enum Op {
     CopyFromClientToServer(usize),
     CopyFromServerToClient(usize),
+    ClientFailed(anyhow::Error),
+    Shutdown,
 }
 
 pub struct PgConn<Conn>
@@ -21,14 +63,129 @@ where
     pub(crate) conn: Conn,
     parser: ProtoParser,
     pub(crate) buffer: BytesMut,
+    // How large `buffer` starts out and may grow. Idle clients hand it back to
+    // the buffer pool, which leaves it empty until the next read.
+    buffer_size: BufferSize,
     incomplete_buffer: BytesMut,
     incomplete_buffer_len: usize,
     pub(crate) is_broken: bool,
     pub(crate) is_active_transaction: bool,
     pub(crate) msgs: VecDeque<ProtoMessage>,
+    // Server connections: the server's current ParameterStatus values.
+    // Client connections: the ParameterStatus values the client was sent.
     pub(crate) server_parameters: BTreeMap<String, String>,
+    // Server connections: the parameters reported right after connecting. These
+    // are used to reset parameters that the next client didn't ask for.
+    pub(crate) default_parameters: BTreeMap<String, String>,
+    // Client connections: session parameters requested by the client.
+    pub(crate) client_parameters: BTreeMap<String, String>,
     pub(crate) startup_message: Option<StartupMessage>,
     pub(crate) created_at: SystemTime,
+    // The real client address. This is the PROXY protocol source address when
+    // the listener expects one, otherwise the socket peer address.
+    pub(crate) client_addr: Option<SocketAddr>,
+    // Server connections: the status of the last ReadyForQuery.
+    pub(crate) transaction_status: char,
+    // Client connections: the error a rejected batch is answered with. The rest
+    // of the batch is dropped until the Sync or Query that ends it shows up.
+    rejected: Option<String>,
+    // Client connections: set once the client used a session feature in pin
+    // mode. The client then keeps its server until it disconnects.
+    pinned: Option<GaugeGuard>,
+    // Client connections: puts Query and Parse messages back together when they
+    // are split across reads, so the analyzer sees every one of them.
+    assembler: Option<Assembler>,
+    // How each message parsed since the last check is sent on.
+    forwards: Vec<Forward>,
+    // The process id and secret key from BackendKeyData: the ones tusq sent a
+    // client, or the ones a server sent tusq.
+    pub(crate) backend_key: Option<(i32, Vec<u8>)>,
+    // Client connections: where cancel requests for the client are looked up.
+    cancel: Option<cancel::Registration>,
+    // Client connections: the address of the listener the client connected to.
+    pub(crate) listener: Option<String>,
+}
+
+// Process ids handed to clients are unique for the life of the process.
+static NEXT_PROCESS_ID: AtomicI32 = AtomicI32::new(1);
+
+// A process id and an unguessable secret key of `length` bytes. The key comes
+// from std's randomly seeded SipHash, which is good enough here.
+fn new_backend_key(length: usize) -> (i32, Vec<u8>) {
+    let process_id = NEXT_PROCESS_ID.fetch_add(1, Ordering::Relaxed);
+    let mut secret_key = Vec::with_capacity(length);
+    while secret_key.len() < length {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_i32(process_id);
+        hasher.write_usize(secret_key.len());
+        secret_key.extend_from_slice(&hasher.finish().to_be_bytes());
+    }
+    secret_key.truncate(length);
+    (process_id, secret_key)
+}
+
+// Whether a Query or Parse depends on session state.
+fn analyze_message(
+    msg_type: char,
+    body: &[u8],
+    track_parameters: &[String],
+) -> Option<(SessionFeature, String)> {
+    let (feature, sql) = match Frontend::parse(msg_type, body) {
+        Ok(Frontend::Query { query }) => (analyzer::analyze(query, track_parameters), query),
+        Ok(Frontend::Parse { name, query, .. }) => (
+            analyzer::analyze_parse(name, query, track_parameters),
+            query,
+        ),
+        _ => return None,
+    };
+    Some((feature?, sql.to_string()))
+}
+
+// The client parameters that are configured to be ignored.
+fn ignored_parameters(
+    client_parameters: &BTreeMap<String, String>,
+    ignored: &[String],
+) -> Vec<String> {
+    client_parameters
+        .keys()
+        .filter(|key| {
+            ignored
+                .iter()
+                .any(|ignored| ignored.eq_ignore_ascii_case(key))
+        })
+        .cloned()
+        .collect()
+}
+
+// The first client parameter that isn't tracked. The application_name is always
+// allowed since it is used by the `application_name` setting too.
+fn unsupported_parameter<'a>(
+    client_parameters: &'a BTreeMap<String, String>,
+    tracked: &[String],
+) -> Option<&'a str> {
+    client_parameters
+        .keys()
+        .find(|key| {
+            !key.eq_ignore_ascii_case(APPLICATION_NAME)
+                && !tracked
+                    .iter()
+                    .any(|tracked| tracked.eq_ignore_ascii_case(key))
+        })
+        .map(|key| key.as_str())
+}
+
+// A string literal that reads the same whatever standard_conforming_strings is.
+fn quote_literal(value: &str) -> String {
+    format!("E'{}'", value.replace('\\', "\\\\").replace('\'', "''"))
+}
+
+// A Query that fails with `message`, sent in place of a rejected batch.
+fn reject_query(message: &str) -> Vec<u8> {
+    messages::query(&format!(
+        "DO $tusq$ BEGIN RAISE EXCEPTION USING ERRCODE = '{}', MESSAGE = {}; END $tusq$",
+        sqlstate::FEATURE_NOT_SUPPORTED,
+        quote_literal(message)
+    ))
 }
 
 impl PgConn<TcpStream> {
@@ -44,15 +201,74 @@ impl PgConn<TcpStream> {
             Err(err) => anyhow::bail!("Error checking connection: {:?}", err),
         }
     }
+
+    // Issue the SETs needed so this server's session parameters match `wanted`.
+    // Parameters that already match are skipped, so this is usually a no-op.
+    // Returns the parameters the server reported back while syncing.
+    pub async fn sync_parameters(
+        &mut self,
+        wanted: &BTreeMap<String, String>,
+    ) -> anyhow::Result<BTreeMap<String, String>> {
+        let mut reported = BTreeMap::new();
+        let mut sql = String::new();
+        for (key, value) in wanted.iter() {
+            match self.server_parameters.get(key) {
+                // The server normalizes some values, e.g. "utf8" is reported as "UTF8".
+                Some(current) if current.eq_ignore_ascii_case(value) => continue,
+                _ => {}
+            }
+            sql.push_str(&format!("SET {} TO {};", key, quote_literal(value)));
+        }
+        if sql.is_empty() {
+            return Ok(reported);
+        }
+
+        log::trace!("Syncing server parameters: {}", sql);
+        write_all_with_timeout(&mut self.conn, &messages::query(&sql), None).await?;
+
+        // Swallow the responses. None of these are meant for the client.
+        let mut error_message = None;
+        loop {
+            self.read_and_parse().await?;
+            while let Some(msg) = self.msgs.pop_front() {
+                match msg.msg_type() {
+                    'E' => error_message = Some(msg.error_message(&self.buffer)),
+                    'S' => {
+                        if let Some((key, value)) = msg.server_parameter(&self.buffer) {
+                            reported.insert(key, value);
+                        }
+                    }
+                    'Z' => {
+                        if let Some(error_message) = error_message {
+                            anyhow::bail!(
+                                "Error from server syncing parameters: {:?}",
+                                error_message
+                            );
+                        }
+
+                        // Not every parameter is reported back by the server, so
+                        // remember what was set.
+                        for (key, value) in wanted.iter() {
+                            self.server_parameters.insert(key.clone(), value.clone());
+                        }
+                        for (key, value) in reported.iter() {
+                            self.server_parameters.insert(key.clone(), value.clone());
+                        }
+                        return Ok(reported);
+                    }
+                    _ => { /* Ignore CommandComplete, etc. */ }
+                }
+            }
+        }
+    }
 }
 
 impl<Conn> PgConn<Conn>
 where
     Conn: AsyncRead + AsyncWrite + Sized + Unpin,
 {
-    pub fn new(conn: Conn) -> anyhow::Result<Self> {
-        let mut buffer = BytesMut::with_capacity(8192);
-        buffer.resize(8192, 0);
+    pub fn new(conn: Conn, buffer_size: BufferSize) -> anyhow::Result<Self> {
+        let buffer = BUFFERS.take(buffer_size.initial);
 
         let mut incomplete_buffer = BytesMut::with_capacity(8);
         incomplete_buffer.resize(8, 0);
@@ -60,6 +276,7 @@ where
         Ok(Self {
             conn,
             buffer,
+            buffer_size,
             incomplete_buffer,
             incomplete_buffer_len: 0,
             is_broken: false,
@@ -67,11 +284,40 @@ where
             parser: ProtoParser::new(),
             msgs: VecDeque::new(),
             server_parameters: BTreeMap::new(),
+            default_parameters: BTreeMap::new(),
+            client_parameters: BTreeMap::new(),
             startup_message: None,
             created_at: SystemTime::now(),
+            client_addr: None,
+            transaction_status: 'I',
+            rejected: None,
+            pinned: None,
+            assembler: None,
+            forwards: vec![],
+            backend_key: None,
+            cancel: None,
+            listener: None,
         })
     }
 
+    pub fn is_pinned(&self) -> bool {
+        self.pinned.is_some()
+    }
+
+    // Hand the buffer to the buffer pool while there is nothing to read. The next
+    // read takes one back.
+    pub fn release_buffer(&mut self) {
+        let buffer = std::mem::take(&mut self.buffer);
+        BUFFERS.give(buffer, self.buffer_size);
+    }
+
+    // Swap a buffer that grew for a bulk result for one of the initial size.
+    pub fn shrink_buffer(&mut self) {
+        if self.buffer.len() > self.buffer_size.initial {
+            self.buffer = BUFFERS.take(self.buffer_size.initial);
+        }
+    }
+
     pub fn database_name(&self) -> Option<String> {
         if let Some(ref startup_message) = self.startup_message {
             return Some(
@@ -85,18 +331,216 @@ where
         None
     }
 
+    // The session parameters this client expects a server to have: the value the
+    // client asked for or, when it didn't ask, the server's value at connect time.
+    pub fn wanted_parameters(
+        &self,
+        tracked: &[String],
+        defaults: &BTreeMap<String, String>,
+    ) -> BTreeMap<String, String> {
+        let mut wanted = BTreeMap::new();
+        for key in tracked.iter() {
+            // Clients don't always use the canonical casing (e.g. "datestyle").
+            let value = self
+                .client_parameters
+                .iter()
+                .find(|(client_key, _)| client_key.eq_ignore_ascii_case(key))
+                .map(|(_, value)| value)
+                .or_else(|| defaults.get(key));
+
+            if let Some(value) = value {
+                wanted.insert(key.clone(), value.clone());
+            }
+        }
+        wanted
+    }
+
+    // Record a new value for a tracked session parameter, replacing any entry
+    // that only differs by case.
+    pub fn set_client_parameter(&mut self, key: &str, value: &str) {
+        self.client_parameters
+            .retain(|client_key, _| !client_key.eq_ignore_ascii_case(key));
+        self.client_parameters.insert(key.into(), value.into());
+    }
+
+    pub fn client_parameter(&self, key: &str) -> Option<&str> {
+        self.client_parameters
+            .iter()
+            .find(|(client_key, _)| client_key.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+
     pub async fn write_auth_ok(&mut self) -> anyhow::Result<()> {
         let msg = messages::auth_ok();
         write_all_with_timeout(&mut self.conn, &msg, None).await?;
         Ok(())
     }
 
-    pub async fn write_ready_for_query(&mut self) -> anyhow::Result<()> {
-        let msg = messages::ready_for_query();
+    pub async fn write_ready_for_query(&mut self, status: char) -> anyhow::Result<()> {
+        let msg = messages::ready_for_query(status);
         write_all_with_timeout(&mut self.conn, &msg, None).await?;
         Ok(())
     }
 
+    pub async fn write_error(&mut self, err: &PgError) -> anyhow::Result<()> {
+        write_all_with_timeout(&mut self.conn, &err.to_message(), None).await?;
+        Ok(())
+    }
+
+    // Tell the client why its connection is about to be closed. This is best
+    // effort: the client might be gone already.
+    pub async fn report_error(&mut self, err: &anyhow::Error) {
+        if self.is_broken {
+            return;
+        }
+        let pg_error = PgError::from_anyhow(err);
+        if let Err(write_err) = self.write_error(&pg_error).await {
+            log::trace!("Could not send error to client: {:?}", write_err);
+        }
+    }
+
+    // Send the client's cancel requests to `server_conn` while the returned
+    // guard is held.
+    fn cancel_target(&self, server_conn: &PgConn<TcpStream>) -> Option<cancel::Checkout> {
+        let (process_id, secret_key) = server_conn.backend_key.clone()?;
+        let target = cancel::Target {
+            addr: server_conn.conn.peer_addr().ok()?,
+            process_id,
+            secret_key,
+        };
+        Some(self.cancel.as_ref()?.checkout(target))
+    }
+
+    // Check out a server connection. When the pool can't hand one out (breaker open,
+    // server down) the client is told why with a connection_failure error.
+    async fn checkout<'a>(
+        &mut self,
+        pool: &'a ServerPool,
+    ) -> anyhow::Result<PooledConnection<'a, PgConnPool>> {
+        match pool.get().await {
+            Ok(server_conn) => Ok(server_conn),
+            Err(err) => Err(PgError::fatal(
+                sqlstate::CONNECTION_FAILURE,
+                format!("tusq: could not connect to server: {}", err),
+            )
+            .into()),
+        }
+    }
+
+    // Decide what happens to a client that used a session feature. Returns the
+    // error to reject its batch with, if it is rejected.
+    fn on_session_feature(
+        &mut self,
+        mode: SessionFeatureMode,
+        feature: SessionFeature,
+        sql: &str,
+    ) -> Option<String> {
+        Stats::incr(&STATS.session_features_detected);
+        if mode == SessionFeatureMode::Pin {
+            log::info!(
+                "Pinning client {:?} to its server after it used a {}: {:?}",
+                self.client_addr,
+                feature.description(),
+                sql
+            );
+            Stats::incr(&STATS.pins_total);
+            self.pinned = Some(GaugeGuard::new(&STATS.pinned_clients));
+            return None;
+        }
+
+        log::warn!(
+            "Client {:?} used a {} which doesn't work with transaction pooling: {:?}",
+            self.client_addr,
+            feature.description(),
+            sql
+        );
+        if mode == SessionFeatureMode::Warn {
+            return None;
+        }
+
+        Stats::incr(&STATS.session_features_rejected);
+        Some(format!(
+            "tusq: {} is not supported in transaction pooling mode",
+            feature.description()
+        ))
+    }
+
+    // Run the session feature analyzer over the client messages of the last read
+    // and work out what is sent to the server. Returns None when that is the read
+    // as is, which is the usual case. `msgs` is left with what is sent.
+    //
+    // A rejected statement is dropped along with the rest of its batch, up to the
+    // Sync or Query that ends it, like postgres skips the rest of a failed batch.
+    // In its place the server runs a query that fails with the rejection. So the
+    // client gets one ErrorResponse and ReadyForQuery per batch, in order with
+    // the replies to its other messages, and a transaction it is in fails just
+    // like it would on postgres.
+    fn check_session_features(
+        &mut self,
+        mode: SessionFeatureMode,
+        track_parameters: &[String],
+    ) -> Option<Vec<u8>> {
+        // Clients that connected while the analyzer was off send every read as is.
+        self.assembler.as_ref()?;
+
+        let msgs = std::mem::take(&mut self.msgs);
+        let forwards = std::mem::take(&mut self.forwards);
+        // Everything up to the first message that isn't sent as read goes as is.
+        let mut outgoing: Option<Vec<u8>> = None;
+        for (msg, forward) in msgs.into_iter().zip(forwards) {
+            let (start, end) = match msg {
+                ProtoMessage::Message(_, start, end) => (start, end),
+                ProtoMessage::Partial(_, start, end) => (start, end),
+                ProtoMessage::PartialComplete(_, end) => (0, end),
+            };
+
+            let body = match forward {
+                Forward::Buffer if msg.is_complete() => msg.body(&self.buffer),
+                Forward::Assembled(ref body) => Some(&body[..]),
+                _ => None,
+            };
+            let analyzed = match body {
+                Some(body) if mode != SessionFeatureMode::Off && !self.is_pinned() => {
+                    analyze_message(msg.msg_type(), body, track_parameters)
+                }
+                _ => None,
+            };
+            if let Some((feature, sql)) = analyzed {
+                if self.rejected.is_none() {
+                    self.rejected = self.on_session_feature(mode, feature, &sql);
+                }
+            }
+
+            if matches!(forward, Forward::Buffer) && self.rejected.is_none() {
+                if let Some(ref mut outgoing) = outgoing {
+                    outgoing.extend_from_slice(&self.buffer[start..=end]);
+                }
+                self.msgs.push_back(msg);
+                continue;
+            }
+
+            let outgoing = outgoing.get_or_insert_with(|| self.buffer[..start].to_vec());
+            if let Some(ref message) = self.rejected {
+                if msg.ends_message() && matches!(msg.msg_type(), 'S' | 'Q') {
+                    let query = reject_query(message);
+                    outgoing.extend_from_slice(&query);
+                    // Only the type and size of this message are looked at.
+                    self.msgs
+                        .push_back(ProtoMessage::Message('Q', 0, query.len() - 1));
+                    self.rejected = None;
+                }
+                continue;
+            }
+            if let Forward::Assembled(body) = forward {
+                outgoing.push(msg.msg_type() as u8);
+                outgoing.extend_from_slice(&(body.len() as i32 + 4).to_be_bytes());
+                outgoing.extend_from_slice(&body);
+            }
+            self.msgs.push_back(msg);
+        }
+        outgoing
+    }
+
     pub async fn write_server_parameters(
         &mut self,
         params: &BTreeMap<String, String>,
@@ -107,105 +551,261 @@ where
             payload.extend_from_slice(&msg);
         }
         write_all_with_timeout(&mut self.conn, &payload, None).await?;
-        Ok(())
-    }
-
-    async fn read_entire_message(&mut self) -> anyhow::Result<Vec<u8>> {
-        let mut agg_buffer = Vec::new();
 
-        let mut n = self.conn.read(&mut self.buffer).await?;
-        if n == 0 {
-            anyhow::bail!("client disconnected: eof");
+        // Remember what this client has been told.
+        for (key, value) in params.iter() {
+            self.server_parameters.insert(key.clone(), value.clone());
         }
-        let msg_size = match ProtoParser::msg_size(&self.buffer[..n]) {
-            Some(s) => s,
-            None => anyhow::bail!("buffer msg too small"),
-        };
-        let mut remaining = msg_size;
+        Ok(())
+    }
 
+    // Read a single startup packet, however it is split across reads. Clients
+    // wait for an answer after each one, so nothing may follow it.
+    // `pending` holds bytes already read from the client, like those that came
+    // in along with the PROXY header. They are parsed first.
+    async fn read_startup(&mut self, pending: &mut Vec<u8>) -> anyhow::Result<ProtoStartup> {
         loop {
-            remaining = remaining - n;
-            agg_buffer.extend_from_slice(&self.buffer[..n]);
-
-            // Break and return buffer (msg is within buffer).
-            if remaining <= 0 {
-                break;
+            let n = if pending.is_empty() {
+                self.conn.read(&mut self.buffer).await?
+            } else {
+                let n = pending.len();
+                self.buffer[..n].copy_from_slice(pending);
+                pending.clear();
+                n
+            };
+            if n == 0 {
+                self.is_broken = true;
+                anyhow::bail!("client disconnected: eof");
             }
 
-            // Remaining to read.
-            let n_to_read = std::cmp::min(remaining, self.buffer.len());
+            let (n_parsed, startup) =
+                self.parser
+                    .parse_startup(&self.buffer[..n])
+                    .map_err(|err| {
+                        PgError::fatal(sqlstate::PROTOCOL_VIOLATION, format!("tusq: {}", err))
+                    })?;
 
-            // We need to read again (buffer too small).
-            n = self.conn.read(&mut self.buffer[..n_to_read]).await?;
-            if n == 0 {
-                anyhow::bail!("client disconnected: eof");
+            if let Some(startup) = startup {
+                if n_parsed < n {
+                    return Err(PgError::fatal(
+                        sqlstate::PROTOCOL_VIOLATION,
+                        "tusq: unexpected data after startup packet",
+                    )
+                    .into());
+                }
+                return Ok(startup);
             }
         }
-
-        return Ok(agg_buffer);
     }
 
     pub async fn handle_startup(
         &mut self,
         mut pooler: PgPooler,
-    ) -> anyhow::Result<bb8::Pool<PgConnPool>> {
-        let startup_buffer = self.read_entire_message().await?;
-
-        let (_n_parsed, startup) = self.parser.parse_startup(&startup_buffer)?;
-
-        // Check if we received an SSLRequest or StartupMessage.
-        let sm = match startup {
-            Some(ProtoStartup::SSLRequest) => {
-                log::trace!("Client sent an SSLRequest...denying.");
-                // If an SSL request, we'll deny for now and continue.
-                write_all_with_timeout(&mut self.conn, &[b'N'], None).await?;
-
-                // Read and await a startup message after denying SSL.
-                let startup_buffer = self.read_entire_message().await?;
-                let (_n_parsed, startup) = self.parser.parse_startup(&startup_buffer)?;
-
-                // Pluck out the startup message or bail with error message.
-                match startup {
-                    Some(ProtoStartup::Message(startup_message)) => startup_message,
-                    Some(msg) => {
-                        anyhow::bail!("Received invalid startup message from client: {:?}", msg)
+        listener: &Listener,
+    ) -> anyhow::Result<Option<ServerPool>> {
+        // The PROXY header comes before anything postgres related.
+        let mut pending = vec![];
+        if listener.proxy_protocol {
+            let (client_addr, rest) = proxy::read_header(&mut self.conn).await?;
+            pending = rest;
+            if let Some(client_addr) = client_addr {
+                log::trace!(
+                    "PROXY header received: {:?} (peer: {:?})",
+                    client_addr,
+                    self.client_addr
+                );
+                self.client_addr = Some(client_addr);
+            }
+        }
+
+        // Encryption requests are denied, after which the client goes on with
+        // plaintext. Each kind may be asked for once before the StartupMessage.
+        let mut denied_ssl = false;
+        let mut denied_gssenc = false;
+        let mut sm = loop {
+            match self.read_startup(&mut pending).await? {
+                ProtoStartup::SSLRequest if !denied_ssl => {
+                    log::trace!("Client sent an SSLRequest...denying.");
+                    write_all_with_timeout(&mut self.conn, b"N", None).await?;
+                    denied_ssl = true;
+                }
+                ProtoStartup::GSSENCRequest if !denied_gssenc => {
+                    log::trace!("Client sent a GSSENCRequest...denying.");
+                    write_all_with_timeout(&mut self.conn, b"N", None).await?;
+                    denied_gssenc = true;
+                }
+                ProtoStartup::CancelRequest {
+                    process_id,
+                    secret_key,
+                } => {
+                    log::trace!("Cancel request received for process: {}", process_id);
+                    // Postgres never answers a cancel request, whatever came of it.
+                    if let Err(err) = cancel::cancel(process_id, &secret_key).await {
+                        log::warn!("Cancel request failed: {:?}", err);
                     }
-                    None => anyhow::bail!("Missing or incomplete startup message from client"),
+                    return Ok(None);
+                }
+                ProtoStartup::Message(startup_message) => break startup_message,
+                msg => {
+                    return Err(PgError::fatal(
+                        sqlstate::PROTOCOL_VIOLATION,
+                        format!("tusq: unexpected startup packet: {:?}", msg),
+                    )
+                    .into())
                 }
             }
-            Some(ProtoStartup::CancelRequest) => {
-                log::trace!("Cancel request received.");
-                anyhow::bail!("Cancel request is not supported.")
-            }
-            Some(ProtoStartup::Message(startup_message)) => startup_message,
-            None => anyhow::bail!("Missing or incomplete startup message from client"),
         };
         log::trace!("Client sent a StartupMessage: {:?}", &sm);
+
+        // Settle on a protocol version before anything else is sent.
+        if let Some(msg) = sm.negotiate_protocol_version() {
+            log::debug!(
+                "Negotiated protocol version down to 3.{}",
+                sm.protocol_version & 0xFFFF
+            );
+            write_all_with_timeout(&mut self.conn, &msg, None).await?;
+        }
         self.startup_message = Some(sm.clone());
+        self.listener = Some(listener.address.clone());
+
+        // Everything besides the user and database is a session parameter.
+        self.client_parameters = sm.parameters.clone();
+        self.client_parameters.remove("user");
+        self.client_parameters.remove("database");
+
+        // Parameters that aren't tracked would never reach a server, so they are
+        // refused unless the config says to ignore them.
+        let (track_parameters, ignore_parameters) = {
+            let config = pooler.config().get().await;
+            (
+                config.track_parameters.clone(),
+                config.ignore_startup_parameters.clone(),
+            )
+        };
+        for key in ignored_parameters(&self.client_parameters, &ignore_parameters) {
+            log::debug!("Ignoring startup parameter {:?}", key);
+            self.client_parameters.remove(&key);
+        }
+        if let Some(key) = unsupported_parameter(&self.client_parameters, &track_parameters) {
+            return Err(PgError::fatal(
+                sqlstate::PROTOCOL_VIOLATION,
+                format!("tusq: unsupported startup parameter: {}", key),
+            )
+            .into());
+        }
+
+        check_database(&pooler, listener, &sm).await?;
 
         // TODO: Check startup message and configuration to conduct an Authn flow.
         self.write_auth_ok().await?;
 
-        // HACK: This is duplicating work.
-        // Write server parameters from a working real server.. should move later.
+        // Greet the client with the pool's server parameters. Only the first client
+        // (or the first after a config reload) needs a server connection for this.
         let pool = pooler.get_pool(sm.clone()).await?;
-        let server_conn = pool
-            .get()
-            .await
-            .map_err(|err| anyhow::anyhow!("Connection Poool: {:?}", err))?;
-        self.write_server_parameters(&server_conn.server_parameters)
-            .await?;
-        drop(server_conn);
+        let mut server_parameters = match pool.cached_server_parameters().await {
+            Some(server_parameters) => server_parameters,
+            None => {
+                let server_conn = self.checkout(&pool).await?;
+                server_conn.default_parameters.clone()
+            }
+        };
+
+        // Report the tracked parameters the client asked for, as postgres would.
+        // These are applied to the server on checkout.
+        let requested = self.wanted_parameters(&track_parameters, &BTreeMap::new());
+        for (key, value) in requested.into_iter() {
+            if server_parameters.contains_key(&key) {
+                server_parameters.insert(key, value);
+            }
+        }
+        self.write_server_parameters(&server_parameters).await?;
+
+        // Every client gets its own key, sized for its protocol version. Ids are
+        // skipped while a client adopted from an upgrade still holds them.
+        let (process_id, secret_key) = loop {
+            let (process_id, secret_key) = new_backend_key(sm.secret_key_length());
+            if let Some(registration) = cancel::Registration::new(process_id, secret_key.clone()) {
+                self.cancel = Some(registration);
+                break (process_id, secret_key);
+            }
+        };
+        let msg = messages::backend_key_data(process_id, &secret_key);
+        write_all_with_timeout(&mut self.conn, &msg, None).await?;
+        self.backend_key = Some((process_id, secret_key));
 
         // Signal read for query.. should probably move later.
-        self.write_ready_for_query().await?;
+        self.write_ready_for_query('I').await?;
 
         // Return original startup message.
-        Ok(pool)
+        Ok(Some(pool))
+    }
+
+    // What a new process needs to take over this client, when it holds nothing
+    // that can't be handed over: no server, and no messages it started sending.
+    pub fn client_state(&self) -> Option<ClientState> {
+        if self.is_pinned()
+            || self.rejected.is_some()
+            || self.incomplete_buffer_len > 0
+            || !self.msgs.is_empty()
+            || self.parser.partial_remaining().is_some()
+        {
+            return None;
+        }
+
+        let sm = self.startup_message.as_ref()?;
+        let (process_id, secret_key) = self.backend_key.clone()?;
+        Some(ClientState {
+            listener: self.listener.clone()?,
+            client_addr: self.client_addr,
+            protocol_version: sm.protocol_version,
+            process_id,
+            secret_key,
+            startup_parameters: sm.parameters.clone(),
+            client_parameters: self.client_parameters.clone(),
+            server_parameters: self.server_parameters.clone(),
+        })
+    }
+
+    // Take over a client from the process being upgraded. The client already
+    // went through startup there, so it is ready for its next query.
+    pub async fn resume(
+        &mut self,
+        state: ClientState,
+        mut pooler: PgPooler,
+        listener: &Listener,
+    ) -> anyhow::Result<ServerPool> {
+        let sm = StartupMessage {
+            protocol_version: state.protocol_version,
+            parameters: state.startup_parameters,
+        };
+        self.startup_message = Some(sm.clone());
+        self.listener = Some(state.listener);
+        self.client_addr = state.client_addr;
+        self.client_parameters = state.client_parameters;
+        self.server_parameters = state.server_parameters;
+
+        // Keys handed out from here on must not collide with the client's.
+        NEXT_PROCESS_ID.fetch_max(state.process_id.saturating_add(1), Ordering::Relaxed);
+        self.cancel = cancel::Registration::new(state.process_id, state.secret_key.clone());
+        if self.cancel.is_none() {
+            log::warn!(
+                "Process id {} is taken, cancel requests for the client are ignored",
+                state.process_id
+            );
+        }
+        self.backend_key = Some((state.process_id, state.secret_key));
+
+        // The config might have changed with the upgrade.
+        check_database(&pooler, listener, &sm).await?;
+        pooler.get_pool(sm).await
     }
 
     #[inline]
     pub async fn read_and_parse(&mut self) -> anyhow::Result<usize> {
+        if self.buffer.is_empty() {
+            self.buffer = BUFFERS.take(self.buffer_size.initial);
+        }
+
         // Copy any incomplete buffer data to new buffer.
         for idx in 0..self.incomplete_buffer_len {
             self.buffer[idx] = self.incomplete_buffer[idx];
@@ -226,10 +826,22 @@ where
         // Attempt to parse the buffer.
         let n_to_parse = self.incomplete_buffer_len + n;
 
+        let msgs_before = self.msgs.len();
         let n_parsed = self
             .parser
             .parse(&self.buffer[..n_to_parse], &mut self.msgs)?;
 
+        if let Some(ref mut assembler) = self.assembler {
+            for msg in self.msgs.iter().skip(msgs_before) {
+                let forward = match assembler.push(msg, &self.buffer) {
+                    Some((_, Cow::Owned(body))) => Forward::Assembled(body),
+                    None if assembler.is_collecting() => Forward::Held,
+                    _ => Forward::Buffer,
+                };
+                self.forwards.push(forward);
+            }
+        }
+
         // Copy any unparsed bytes to the incomplete buffer which will be copied
         // to the next buffer when this method is called.
         self.incomplete_buffer_len = n_to_parse - n_parsed;
@@ -237,103 +849,475 @@ where
             self.incomplete_buffer[idx] = self.buffer[idx + n_parsed];
         }
 
+        // The read filled the whole buffer, so more is likely waiting. Grow to
+        // read bulk results with fewer syscalls.
+        if n_to_parse == self.buffer.len() {
+            if let Some(size) = self.buffer_size.grow(self.buffer.len()) {
+                self.buffer.resize(size, 0);
+                Stats::incr(&STATS.buffer_grows);
+            }
+        }
+
         // Return only the number of bytes pared.
         Ok(n_parsed)
     }
 }
 
+impl<Conn> Drop for PgConn<Conn>
+where
+    Conn: AsyncRead + AsyncWrite + Sized + Unpin,
+{
+    fn drop(&mut self) {
+        self.release_buffer();
+    }
+}
+
+// Takes a server out of COPY FROM STDIN after the client went away mid copy. If
+// that leaves the server idle, it can go back to the pool.
+async fn abort_copy<Client, Server>(
+    client_conn: &PgConn<Client>,
+    server_conn: &mut PgConn<Server>,
+    copy: &CopyTracker,
+) -> anyhow::Result<()>
+where
+    Client: AsyncRead + AsyncWrite + Sized + Unpin,
+    Server: AsyncRead + AsyncWrite + Sized + Unpin,
+{
+    // The server got the start of a message the client never finished, so it
+    // would take the CopyFail for the rest of it. The connection can't be saved.
+    if let Some((msg_type, remaining)) = client_conn.parser.partial_remaining() {
+        server_conn.is_broken = true;
+        anyhow::bail!(
+            "Client left with {} bytes of a {:?} message still to come",
+            remaining,
+            msg_type
+        );
+    }
+
+    Stats::incr(&STATS.copies_aborted);
+    let msgs = copy.abort_messages("tusq: client disconnected during COPY");
+    write_all_with_timeout(
+        &mut server_conn.conn,
+        &msgs,
+        Some(std::time::Duration::from_secs(5)),
+    )
+    .await?;
+
+    loop {
+        let read = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            server_conn.read_and_parse(),
+        );
+        match read.await {
+            Ok(res) => res?,
+            Err(_) => anyhow::bail!("Timed out waiting for the server to abort COPY"),
+        };
+        while let Some(msg) = server_conn.msgs.pop_front() {
+            if msg.msg_type() == 'Z' {
+                if let Some(transaction_status) = msg.transaction_type(&server_conn.buffer) {
+                    server_conn.transaction_status = transaction_status;
+                }
+                if server_conn.transaction_status == 'I' {
+                    server_conn.is_active_transaction = false;
+                    server_conn.shrink_buffer();
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Moves the rest of a large DataRow or CopyData message that is still coming
+// from the server straight to the client with splice(2). tusq never looks at
+// these, so only the parser has to know the bytes went by.
+#[cfg(target_os = "linux")]
+async fn splice_message<Conn>(
+    client_conn: &mut PgConn<Conn>,
+    server_conn: &mut PgConn<TcpStream>,
+    pipe: &mut Option<crate::splice::Pipe>,
+    threshold: usize,
+) -> anyhow::Result<()>
+where
+    Conn: AsyncRead + AsyncWrite + AsTcpStream + Sized + Unpin,
+{
+    let (msg_type, remaining) = match server_conn.parser.partial_remaining() {
+        Some((msg_type, remaining)) if msg_type == 'D' || msg_type == 'd' => (msg_type, remaining),
+        _ => return Ok(()),
+    };
+    if remaining < threshold || server_conn.incomplete_buffer_len > 0 {
+        return Ok(());
+    }
+    let client = match client_conn.conn.as_tcp_stream() {
+        Some(client) => client,
+        None => return Ok(()),
+    };
+    if pipe.is_none() {
+        *pipe = Some(crate::splice::Pipe::new()?);
+    }
+    let pipe = pipe.as_ref().expect("pipe was created");
+
+    crate::splice::splice_exact(&server_conn.conn, client, pipe, remaining).await?;
+    server_conn.parser.skip(remaining)?;
+
+    Stats::add(&STATS.splice_bytes, remaining as u64);
+    if msg_type == 'd' {
+        Stats::add(&STATS.copy_out_bytes, remaining as u64);
+    }
+    Ok(())
+}
+
+// The client is told about server failures with a connection_failure error.
+// Only expose the databases a listener allows and that are configured, either by
+// name or through the "*" template.
+async fn check_database(
+    pooler: &PgPooler,
+    listener: &Listener,
+    sm: &StartupMessage,
+) -> anyhow::Result<()> {
+    let dbname = sm.database_name().unwrap_or_default();
+    let is_configured = pooler.config().get().await.database(&dbname).is_some();
+    if !listener.allows_database(&dbname) || !is_configured {
+        log::debug!(
+            "Database {:?} is not configured or not allowed on {}",
+            dbname,
+            listener.address
+        );
+        return Err(PgError::fatal(
+            sqlstate::INVALID_CATALOG_NAME,
+            format!("no such database: {}", dbname),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn is_shutting_down(shutdown: &watch::Receiver<String>) -> bool {
+    !shutdown.borrow().is_empty()
+}
+
+pub fn admin_shutdown() -> anyhow::Error {
+    PgError::fatal(
+        sqlstate::ADMIN_SHUTDOWN,
+        "tusq: terminating connection due to administrator command",
+    )
+    .into()
+}
+
+// Resolves once `shutdown_timeout_ms` has passed during a shutdown.
+pub async fn force_shutdown(mut shutdown: watch::Receiver<String>) {
+    while *shutdown.borrow() != FORCE_SHUTDOWN {
+        if shutdown.changed().await.is_err() {
+            return futures::future::pending().await;
+        }
+    }
+}
+
+// Called when tusq shuts down while the client is between transactions. During
+// an upgrade the client is handed to the new process. Otherwise (or when that
+// fails) it is told the connection is closing, as postgres would.
+async fn leave<Conn>(
+    client_conn: &mut PgConn<Conn>,
+    shutdown: &watch::Receiver<String>,
+    migrations: &Migrations,
+) -> anyhow::Result<()>
+where
+    Conn: AsyncRead + AsyncWrite + AsTcpStream + Sized + Unpin,
+{
+    let is_migrating = *shutdown.borrow() == upgrade::MIGRATE_CLIENTS;
+    if is_migrating && migrate(client_conn, migrations).await {
+        return Ok(());
+    }
+    client_conn.report_error(&admin_shutdown()).await;
+    Ok(())
+}
+
+// Hand the client to the new process. Returns whether it was handed over.
+async fn migrate<Conn>(client_conn: &mut PgConn<Conn>, migrations: &Migrations) -> bool
+where
+    Conn: AsyncRead + AsyncWrite + AsTcpStream + Sized + Unpin,
+{
+    let state = match client_conn.client_state() {
+        Some(state) => state,
+        None => return false,
+    };
+    let fd = match client_conn.conn.as_tcp_stream() {
+        Some(conn) => conn.as_raw_fd(),
+        None => return false,
+    };
+
+    let (done, result) = oneshot::channel();
+    let migration = Migration { state, fd, done };
+    if migrations.send(migration).await.is_err() {
+        return false;
+    }
+    match result.await {
+        Ok(Ok(())) => {
+            // The new process has its own copy of the socket. Closing ours must
+            // not write anything to the client.
+            client_conn.is_broken = true;
+            log::info!(
+                "Client handed to the new process: {:?}",
+                client_conn.client_addr
+            );
+            true
+        }
+        Ok(Err(err)) => {
+            log::warn!("Failed to hand client to the new process: {:?}", err);
+            false
+        }
+        Err(_) => {
+            log::warn!("Failed to hand client to the new process: sender gone");
+            false
+        }
+    }
+}
+
+fn lost_server(err: anyhow::Error) -> anyhow::Error {
+    PgError::fatal(
+        sqlstate::CONNECTION_FAILURE,
+        format!("tusq: lost connection to server: {}", err),
+    )
+    .into()
+}
+
 // Manage the entire client life-cycle.
 pub async fn spawn<Conn>(
     mut client_conn: PgConn<Conn>,
-    pool: bb8::Pool<PgConnPool>,
+    pool: ServerPool,
+    config: UpdatableConfig,
+    shutdown: tokio::sync::watch::Receiver<String>,
+    migrations: Migrations,
+) -> anyhow::Result<()>
+where
+    Conn: AsyncRead + AsyncWrite + Readable + AsTcpStream + Sized + Unpin,
+{
+    let res = proxy_transactions(&mut client_conn, pool, config, shutdown, migrations).await;
+    if let Err(ref err) = res {
+        client_conn.report_error(err).await;
+    }
+    res
+}
+
+async fn proxy_transactions<Conn>(
+    client_conn: &mut PgConn<Conn>,
+    pool: ServerPool,
+    config: UpdatableConfig,
     mut shutdown: tokio::sync::watch::Receiver<String>,
+    migrations: Migrations,
 ) -> anyhow::Result<()>
 where
-    Conn: AsyncRead + AsyncWrite + Sized + Unpin,
+    Conn: AsyncRead + AsyncWrite + Readable + AsTcpStream + Sized + Unpin,
 {
+    // Only clients that connect while the analyzer is on are analyzed.
+    if config.get().await.session_features != SessionFeatureMode::Off {
+        client_conn.assembler = Some(Assembler::new(&['Q', 'P'], MAX_ANALYZED_MESSAGE_SIZE));
+    }
+
+    // Created the first time a message is spliced.
+    #[cfg(target_os = "linux")]
+    let mut pipe = None;
+
     // Outter transaction loop.
     loop {
+        // Shutdown might have started while the client was in a transaction.
+        if is_shutting_down(&shutdown) {
+            return leave(client_conn, &shutdown, &migrations).await;
+        }
+
+        // Idle clients don't need a buffer until they send something.
+        client_conn.release_buffer();
+        tokio::select! {
+            _ = shutdown.changed() => return leave(client_conn, &shutdown, &migrations).await,
+            res = client_conn.conn.readable() => res?,
+        };
+
         // Read and parse. Bail if we get an EOF. Close connection if tusq is shutting down.
         let n = tokio::select! {
-            _ = shutdown.changed() => return Ok(()),
+            _ = shutdown.changed() => return leave(client_conn, &shutdown, &migrations).await,
             res = client_conn.read_and_parse() => res?,
         };
         if n == 0 {
             return Ok(());
         }
 
+        let (track_parameters, application_name, session_features, splice_threshold) = {
+            let config = config.get().await;
+            (
+                config.track_parameters.clone(),
+                config.application_name.clone(),
+                config.session_features,
+                config.splice_threshold(),
+            )
+        };
+
+        // A read that only held back part of a message has nothing to send yet.
+        let outgoing = client_conn.check_session_features(session_features, &track_parameters);
+        if matches!(outgoing, Some(ref bytes) if bytes.is_empty()) {
+            client_conn.msgs.clear();
+            continue;
+        }
+
         // Check to ensure it signals the beginning of a txn. Close otherwise.
+        let mut pipeline = Pipeline::default();
         while let Some(msg) = client_conn.msgs.pop_front() {
+            if msg.ends_message() {
+                pipeline.client_msg(msg.msg_type());
+            }
             match msg.msg_type() {
                 // We only check for complete or partial messages here. The point is to
                 // detect the beginning of a transaction.
-                'P' | 'S' | 'Q' | 'D' | 'B' | 'E' => {}
+                'P' | 'S' | 'Q' | 'D' | 'B' | 'E' | 'C' | 'H' | 'F' => {}
                 'X' => {
                     log::info!("Client sent close request. Closing connection.");
                     return Ok(());
                 }
                 msg_type => {
-                    panic!(
-                        "Client sent non-query or close command ({}). Closing connection.",
-                        msg_type,
-                    );
+                    return Err(PgError::fatal(
+                        sqlstate::PROTOCOL_VIOLATION,
+                        format!(
+                            "tusq: unexpected message type {:?} outside of a transaction",
+                            msg_type
+                        ),
+                    )
+                    .into());
                 }
             }
         }
 
         // Keep valid lifetime for the startup message.
-        let mut server_conn = pool
-            .get()
-            .await
-            .map_err(|err| anyhow::anyhow!("Connection Poool: {:?}", err))?;
+        let mut server_conn = client_conn.checkout(&pool).await?;
 
         // Mark that we're entering a transaction for the connection pool to clean up.
         server_conn.is_active_transaction = true;
 
+        // Cancel requests from the client go to this server until it is released.
+        let _cancel = client_conn.cancel_target(&server_conn);
+
+        // Replay the client's session parameters onto this server connection.
+        let mut wanted =
+            client_conn.wanted_parameters(&track_parameters, &server_conn.default_parameters);
+        let client_application_name = client_conn.client_parameter(APPLICATION_NAME);
+        if let Some(value) =
+            application_name.server_value(client_application_name, client_conn.client_addr)
+        {
+            wanted.insert(APPLICATION_NAME.into(), value);
+        }
+        let reported = server_conn.sync_parameters(&wanted).await?;
+
+        // Adopt the server's spelling of values the client asked for so they
+        // compare equal next time. The application_name the server has might be
+        // rewritten, so the client keeps its own.
+        for (key, value) in reported.iter() {
+            if key != APPLICATION_NAME && client_conn.client_parameter(key).is_some() {
+                client_conn.set_client_parameter(key, value);
+            }
+        }
+
+        // Let the client know about parameters that differ from what it was told,
+        // e.g. after a reset to server defaults or when landing on another server.
+        let mut changed = BTreeMap::new();
+        for (key, value) in client_conn.server_parameters.iter() {
+            match server_conn.server_parameters.get(key) {
+                Some(server_value) if server_value != value && key != APPLICATION_NAME => {
+                    changed.insert(key.clone(), server_value.clone());
+                }
+                _ => {}
+            }
+        }
+        if !changed.is_empty() {
+            client_conn.write_server_parameters(&changed).await?;
+        }
+
         // Write those N bytes to the server.
         write_all_with_timeout(
             &mut server_conn.conn,
-            &client_conn.buffer[..n],
+            outgoing.as_deref().unwrap_or(&client_conn.buffer[..n]),
             Some(std::time::Duration::from_secs(5)),
         )
-        .await?;
+        .await
+        .map_err(lost_server)?;
 
         // Proxy between client and server until the client or server ends the txn.
         'transaction: loop {
             // Read from either socket and parse msgs.
             // We use an "op" here to avoid the annoying double-owned inside/ outside
             // the match / case clause.
-            let op = match select(
+            let is_idle_pin = client_conn.is_pinned()
+                && server_conn.transaction_status == 'I'
+                && pipeline.is_idle();
+
+            // A pinned client never goes back to the outer loop, so it has to
+            // watch for shutdown here while it is between transactions.
+            if is_idle_pin && is_shutting_down(&shutdown) {
+                return leave(client_conn, &shutdown, &migrations).await;
+            }
+
+            let read = select(
                 Box::pin(client_conn.read_and_parse()),
                 Box::pin(server_conn.read_and_parse()),
-            )
-            .await
-            {
-                // Success case.
-                Either::Left((Ok(client_n), _dropped_server_read)) => {
-                    Op::CopyFromClientToServer(client_n)
-                }
-                Either::Right((Ok(server_n), _dropped_client_read)) => {
-                    Op::CopyFromServerToClient(server_n)
-                }
+            );
+            let op = tokio::select! {
+                _ = shutdown.changed() => Op::Shutdown,
+                res = read => match res {
+                    // Success case.
+                    Either::Left((Ok(client_n), _dropped_server_read)) => {
+                        Op::CopyFromClientToServer(client_n)
+                    }
+                    Either::Right((Ok(server_n), _dropped_client_read)) => {
+                        Op::CopyFromServerToClient(server_n)
+                    }
 
-                // Error case.
-                Either::Left((Err(err), _)) | Either::Right((Err(err), _)) => return Err(err),
+                    // Error case.
+                    Either::Left((Err(err), _)) => Op::ClientFailed(err),
+                    Either::Right((Err(err), _)) => return Err(lost_server(err)),
+                },
             };
 
             // Copy all pending buffer from one to the other.
             match op {
+                // Clients in a transaction get to finish it, unless the shutdown
+                // deadline has passed. Nothing was read, so just start over.
+                Op::Shutdown => {
+                    if *shutdown.borrow() == FORCE_SHUTDOWN {
+                        log::warn!(
+                            "Closing client mid transaction at the shutdown deadline: {:?}",
+                            client_conn.client_addr
+                        );
+                        return Err(admin_shutdown());
+                    }
+                    continue;
+                }
+                // A server waiting on COPY rows is told the copy failed, so the
+                // connection can still be reused.
+                Op::ClientFailed(err) => {
+                    if pipeline.copy.is_waiting_for_client() && !client_conn.is_pinned() {
+                        if let Err(copy_err) =
+                            abort_copy(client_conn, &mut server_conn, &pipeline.copy).await
+                        {
+                            log::warn!("Failed to abort COPY: {:?}", copy_err);
+                        }
+                    }
+                    return Err(err);
+                }
                 Op::CopyFromClientToServer(n) => {
+                    let outgoing =
+                        client_conn.check_session_features(session_features, &track_parameters);
                     write_all_with_timeout(
                         &mut server_conn.conn,
-                        &client_conn.buffer[..n],
+                        outgoing.as_deref().unwrap_or(&client_conn.buffer[..n]),
                         Some(std::time::Duration::from_secs(30)),
                     )
-                    .await?;
+                    .await
+                    .map_err(lost_server)?;
                 }
                 Op::CopyFromServerToClient(n) => {
                     write_all_with_timeout(&mut client_conn.conn, &server_conn.buffer[..n], None)
                         .await?;
+
+                    #[cfg(target_os = "linux")]
+                    if let Some(threshold) = splice_threshold {
+                        splice_message(client_conn, &mut server_conn, &mut pipe, threshold).await?;
+                    }
                 }
             };
 
@@ -343,17 +1327,64 @@ where
             while let Some(msg) = server_conn.msgs.pop_front() {
                 // println!("SRV->CLT: {:?}", msg);
 
+                if msg.ends_message() {
+                    pipeline.server_msg(msg.msg_type());
+                }
                 match msg.msg_type() {
+                    'd' => Stats::add(&STATS.copy_out_bytes, msg.size() as u64),
                     'Z' => {
-                        if let Some('I') = msg.transaction_type(&server_conn.buffer) {
+                        let transaction_status = msg.transaction_type(&server_conn.buffer);
+                        if let Some(transaction_status) = transaction_status {
+                            server_conn.transaction_status = transaction_status;
+                        }
+                        // A pipelining client may still be waiting on responses for
+                        // requests it sent after this one.
+                        if !pipeline.is_idle() {
+                            log::trace!(
+                                "Requests still in flight (error recovery: {})",
+                                pipeline.is_error_recovery()
+                            );
+                            continue;
+                        }
+                        if let Some('I') = transaction_status {
+                            // A pinned server carries the client's session state. It
+                            // is closed rather than reused once the client leaves.
+                            if client_conn.is_pinned() {
+                                server_conn.is_broken = true;
+                                continue;
+                            }
+
                             // Signal the connection is safe to be used by a new client.
                             server_conn.is_active_transaction = false;
+                            server_conn.shrink_buffer();
                             break 'transaction;
                         }
                     }
+                    'S' => {
+                        // A session parameter changed, most likely a SET from the client.
+                        // The client sees this message as it is proxied, so just keep track.
+                        // A SET of a parameter postgres doesn't report goes unnoticed.
+                        if let Some((key, value)) = msg.server_parameter(&server_conn.buffer) {
+                            let is_tracked = key == APPLICATION_NAME
+                                || track_parameters
+                                    .iter()
+                                    .any(|tracked| tracked.eq_ignore_ascii_case(&key));
+                            if is_tracked {
+                                client_conn.set_client_parameter(&key, &value);
+                            }
+                            server_conn
+                                .server_parameters
+                                .insert(key.clone(), value.clone());
+                            client_conn.server_parameters.insert(key, value);
+                        }
+                    }
                     'X' => {
-                        log::warn!("Server is closing the connection!");
-                        panic!("Server is closing early");
+                        log::warn!("Server sent a close request mid transaction!");
+                        return Err(PgError::fatal(
+                            sqlstate::PROTOCOL_VIOLATION,
+                            "tusq: unexpected message from server",
+                        )
+                        .into());
                     }
                     _ => { /* Proxy and continue. */ }
                 }
@@ -363,10 +1394,20 @@ where
             while let Some(msg) = client_conn.msgs.pop_front() {
                 // println!("CLT->SRV: {:?}", msg);
 
+                if msg.ends_message() {
+                    pipeline.client_msg(msg.msg_type());
+                }
                 match msg.msg_type() {
+                    'd' => Stats::add(&STATS.copy_in_bytes, msg.size() as u64),
                     'X' => {
-                        log::warn!("Client is closing the connection!");
-                        panic!("Client is closing early");
+                        // The server connection is dropped rather than reused since
+                        // it is still mid transaction (or pinned).
+                        if client_conn.is_pinned() {
+                            log::info!("Pinned client sent close request. Closing connection.");
+                        } else {
+                            log::warn!("Client sent close request mid transaction!");
+                        }
+                        return Ok(());
                     }
                     _ => { /* Proxy and continue. */ }
                 }
@@ -376,9 +1417,95 @@ where
 }
 
 pub mod net {
+    use crate::config::{Listener, TcpOptions};
+    use async_trait::async_trait;
+    use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+    use std::net::SocketAddr;
+    use std::time::Duration;
     use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
     use tokio::time;
 
+    // Wait until a connection has something to read, without reading it.
+    #[async_trait]
+    pub trait Readable {
+        async fn readable(&self) -> std::io::Result<()>;
+    }
+
+    #[async_trait]
+    impl Readable for TcpStream {
+        async fn readable(&self) -> std::io::Result<()> {
+            TcpStream::readable(self).await
+        }
+    }
+
+    // The TCP socket behind a connection, for fast paths that need one.
+    pub trait AsTcpStream {
+        fn as_tcp_stream(&self) -> Option<&TcpStream>;
+    }
+
+    impl AsTcpStream for TcpStream {
+        fn as_tcp_stream(&self) -> Option<&TcpStream> {
+            Some(self)
+        }
+    }
+
+    // Bind a listening socket, with SO_REUSEPORT when the listener asks for it.
+    pub fn bind_listener(listener: &Listener) -> anyhow::Result<TcpListener> {
+        let addr = listener.address.parse::<SocketAddr>()?;
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        if listener.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        Ok(TcpListener::from_std(socket.into())?)
+    }
+
+    // Apply the configured socket options to a client or server connection.
+    pub fn configure_socket(conn: &TcpStream, options: &TcpOptions) -> anyhow::Result<()> {
+        // Disable nagle!
+        conn.set_nodelay(true)?;
+
+        let socket = SockRef::from(conn);
+
+        if options.keepalive {
+            let mut keepalive = TcpKeepalive::new();
+            if let Some(idle) = options.keepalive_idle_ms {
+                keepalive = keepalive.with_time(Duration::from_millis(idle));
+            }
+            if let Some(interval) = options.keepalive_interval_ms {
+                keepalive = keepalive.with_interval(Duration::from_millis(interval));
+            }
+            if let Some(count) = options.keepalive_count {
+                keepalive = keepalive.with_retries(count);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+
+        if let Some(user_timeout) = options.user_timeout_ms {
+            #[cfg(target_os = "linux")]
+            socket.set_tcp_user_timeout(Some(Duration::from_millis(user_timeout)))?;
+
+            #[cfg(not(target_os = "linux"))]
+            log::warn!(
+                "Ignoring user_timeout_ms={}: only supported on linux",
+                user_timeout
+            );
+        }
+
+        if let Some(size) = options.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = options.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+
+        Ok(())
+    }
+
     // Add helper function to handle a read with timeout.
     pub async fn read_or_timeout<Conn>(
         conn: &mut Conn,
@@ -427,3 +1554,167 @@ pub mod net {
         Ok(inner)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+
+    fn client() -> (PgConn<DuplexStream>, DuplexStream) {
+        let (conn, peer) = tokio::io::duplex(64 * 1024);
+        let size = BufferSize {
+            initial: 8192,
+            max: 8192,
+        };
+        let mut client_conn = PgConn::new(conn, size).unwrap();
+        client_conn.assembler = Some(Assembler::new(&['Q', 'P'], MAX_ANALYZED_MESSAGE_SIZE));
+        (client_conn, peer)
+    }
+
+    // Read what the peer sent and return what goes to the server, along with
+    // the type of each message sent.
+    async fn forward(client_conn: &mut PgConn<DuplexStream>) -> (Vec<u8>, Vec<char>) {
+        let n = client_conn.read_and_parse().await.unwrap();
+        let outgoing = client_conn
+            .check_session_features(SessionFeatureMode::Reject, &[])
+            .unwrap_or_else(|| client_conn.buffer[..n].to_vec());
+        let msg_types = client_conn
+            .msgs
+            .drain(..)
+            .filter(|msg| msg.ends_message())
+            .map(|msg| msg.msg_type())
+            .collect();
+        (outgoing, msg_types)
+    }
+
+    fn message(msg_type: u8, body: &[u8]) -> Vec<u8> {
+        messages::MessageBuilder::new(msg_type).bytes(body).finish()
+    }
+
+    #[tokio::test]
+    async fn it_drops_the_server_when_the_client_left_mid_copy_data() {
+        let (mut client_conn, mut client_peer) = client();
+        let (server, mut server_peer) = tokio::io::duplex(1024);
+        let size = BufferSize {
+            initial: 8192,
+            max: 8192,
+        };
+        let mut server_conn = PgConn::new(server, size).unwrap();
+        let mut copy = CopyTracker::default();
+        copy.client_msg('Q');
+        copy.server_msg('G');
+
+        // Half of a CopyData, then the client is gone.
+        client_peer
+            .write_all(&message(b'd', b"1\t2\n")[..7])
+            .await
+            .unwrap();
+        drop(client_peer);
+        client_conn.read_and_parse().await.unwrap();
+        assert!(client_conn.read_and_parse().await.is_err());
+
+        assert!(abort_copy(&client_conn, &mut server_conn, &copy)
+            .await
+            .is_err());
+        assert!(server_conn.is_broken);
+
+        // Nothing was sent to the server.
+        drop(server_conn);
+        let mut sent = vec![];
+        server_peer.read_to_end(&mut sent).await.unwrap();
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn it_refuses_untracked_startup_parameters() {
+        let tracked = vec!["DateStyle".to_string()];
+        let mut params = BTreeMap::new();
+        params.insert("application_name".to_string(), "psql".to_string());
+        params.insert("datestyle".to_string(), "ISO".to_string());
+        assert_eq!(unsupported_parameter(&params, &tracked), None);
+
+        params.insert("extra_float_digits".to_string(), "3".to_string());
+        params.insert("options".to_string(), "-c work_mem=1GB".to_string());
+        assert_eq!(
+            unsupported_parameter(&params, &tracked),
+            Some("extra_float_digits")
+        );
+        assert_eq!(
+            ignored_parameters(&params, &["Extra_Float_Digits".to_string()]),
+            vec!["extra_float_digits".to_string()]
+        );
+    }
+
+    #[test]
+    fn it_quotes_literals() {
+        assert_eq!(quote_literal("it's"), "E'it''s'");
+        assert_eq!(quote_literal("C:\\tmp\\"), "E'C:\\\\tmp\\\\'");
+    }
+
+    #[tokio::test]
+    async fn it_rejects_only_the_batch_with_a_session_feature() {
+        let (mut client_conn, mut peer) = client();
+        let mut bytes = messages::query("select 1");
+        bytes.extend_from_slice(&messages::query("listen foo"));
+        bytes.extend_from_slice(&messages::query("select 2"));
+        peer.write_all(&bytes).await.unwrap();
+
+        let (outgoing, msg_types) = forward(&mut client_conn).await;
+        let mut expected = messages::query("select 1");
+        expected.extend_from_slice(&reject_query(
+            "tusq: LISTEN is not supported in transaction pooling mode",
+        ));
+        expected.extend_from_slice(&messages::query("select 2"));
+        assert_eq!(outgoing, expected);
+        assert_eq!(msg_types, vec!['Q', 'Q', 'Q']);
+
+        // The server answers each Query with a ReadyForQuery.
+        let mut pipeline = Pipeline::default();
+        for msg_type in msg_types {
+            pipeline.client_msg(msg_type);
+        }
+        for _ in 0..2 {
+            pipeline.server_msg('Z');
+            assert!(!pipeline.is_idle());
+        }
+        pipeline.server_msg('Z');
+        assert!(pipeline.is_idle());
+    }
+
+    #[tokio::test]
+    async fn it_drops_a_rejected_extended_batch_up_to_its_sync() {
+        let (mut client_conn, mut peer) = client();
+        let mut bytes = message(b'P', b"s1\0listen foo\0\0\0");
+        bytes.extend_from_slice(&message(b'B', b"\0s1\0\0\0\0\0\0\0"));
+        peer.write_all(&bytes).await.unwrap();
+        let (outgoing, msg_types) = forward(&mut client_conn).await;
+        assert!(outgoing.is_empty());
+        assert!(msg_types.is_empty());
+
+        let mut bytes = messages::sync();
+        bytes.extend_from_slice(&messages::query("select 1"));
+        peer.write_all(&bytes).await.unwrap();
+        let (outgoing, msg_types) = forward(&mut client_conn).await;
+        let mut expected = reject_query(
+            "tusq: named prepared statement s1 is not supported in transaction pooling mode",
+        );
+        expected.extend_from_slice(&messages::query("select 1"));
+        assert_eq!(outgoing, expected);
+        assert_eq!(msg_types, vec!['Q', 'Q']);
+    }
+
+    #[tokio::test]
+    async fn it_holds_back_a_query_until_it_is_complete() {
+        let (mut client_conn, mut peer) = client();
+        let query = messages::query("select 1");
+        peer.write_all(&query[..7]).await.unwrap();
+        let (outgoing, msg_types) = forward(&mut client_conn).await;
+        assert!(outgoing.is_empty());
+        assert!(msg_types.is_empty());
+
+        peer.write_all(&query[7..]).await.unwrap();
+        let (outgoing, msg_types) = forward(&mut client_conn).await;
+        assert_eq!(outgoing, query);
+        assert_eq!(msg_types, vec!['Q']);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::proto::messages;
+use std::fmt;
+
+// SQLSTATE codes used by tusq. See the postgres docs, "PostgreSQL Error Codes".
+pub mod sqlstate {
+    pub const FEATURE_NOT_SUPPORTED: &str = "0A000";
+    pub const CONNECTION_FAILURE: &str = "08006";
+    pub const ADMIN_SHUTDOWN: &str = "57P01";
+    pub const PROTOCOL_VIOLATION: &str = "08P01";
+    pub const INVALID_AUTHORIZATION_SPECIFICATION: &str = "28000";
+    pub const INVALID_CATALOG_NAME: &str = "3D000";
+    pub const INTERNAL_ERROR: &str = "XX000";
+}
+
+// An error meant for the client. Anything that ends a client connection can be
+// wrapped in one (it works with anyhow downcasting), and it is sent to the
+// client as an ErrorResponse before the connection is closed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PgError {
+    pub severity: &'static str,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl PgError {
+    // The statement failed, but the session goes on.
+    pub fn error(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            severity: "ERROR",
+            code,
+            message: message.into(),
+        }
+    }
+
+    // The session is over.
+    pub fn fatal(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            severity: "FATAL",
+            code,
+            message: message.into(),
+        }
+    }
+
+    // Find the PgError behind an anyhow error. Anything else is reported as an
+    // internal error.
+    pub fn from_anyhow(err: &anyhow::Error) -> Self {
+        match err.downcast_ref::<PgError>() {
+            Some(pg_error) => pg_error.clone(),
+            None => PgError::fatal(sqlstate::INTERNAL_ERROR, format!("tusq: {}", err)),
+        }
+    }
+
+    pub fn to_message(&self) -> Vec<u8> {
+        messages::error_response(self.severity, self.code, &self.message)
+    }
+}
+
+impl fmt::Display for PgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.severity, self.code, self.message)
+    }
+}
+
+impl std::error::Error for PgError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_can_be_found_behind_anyhow() {
+        let err: anyhow::Error = PgError::fatal(sqlstate::INVALID_CATALOG_NAME, "nope").into();
+        assert_eq!(
+            PgError::from_anyhow(&err),
+            PgError::fatal(sqlstate::INVALID_CATALOG_NAME, "nope")
+        );
+
+        let err = anyhow::anyhow!("boom");
+        assert_eq!(
+            PgError::from_anyhow(&err),
+            PgError::fatal(sqlstate::INTERNAL_ERROR, "tusq: boom")
+        );
+    }
+}
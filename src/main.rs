@@ -1,12 +1,27 @@
+pub mod analyzer;
+pub mod breaker;
+pub mod buffer;
+pub mod cancel;
 pub mod config;
+pub mod copy;
 pub mod core;
+pub mod error;
+pub mod pipeline;
 pub mod pool;
 pub mod proto;
+pub mod proxy;
+#[cfg(target_os = "linux")]
+pub mod splice;
+pub mod stats;
+pub mod systemd;
+pub mod upgrade;
 
 use clap::Parser;
-use config::{Config, UpdatableConfig};
+use config::{Config, Listener, UpdatableConfig};
 use pool::PgPooler;
-use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::signal::unix::{signal, SignalKind};
 
@@ -14,48 +29,88 @@ use tokio::signal::unix::{signal, SignalKind};
 struct Opts {
     #[clap(short, long, default_value = "tusq.toml")]
     config: String,
+    // Take over the listening sockets of the tusq process serving upgrade_socket.
+    #[clap(long)]
+    upgrade: bool,
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn listen_for_clients(
     listener: TcpListener,
+    listener_config: Arc<Listener>,
+    config: UpdatableConfig,
     pooler: PgPooler,
     shutdown: tokio::sync::watch::Receiver<String>,
     worker: waitgroup::Worker,
+    migrations: upgrade::Migrations,
+    heartbeat: systemd::Heartbeat,
 ) -> anyhow::Result<()> {
     loop {
-        let (client_conn, _) = listener.accept().await?;
-        let client_info = format!("{:?}", client_conn);
+        // Beat even without clients, so the watchdog knows the loop isn't stuck.
+        heartbeat.beat();
+        let (client_conn, client_addr) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = tokio::time::sleep(systemd::HEARTBEAT_PERIOD) => continue,
+        };
+        let mut client_info = client_addr.to_string();
         log::info!("Client connected: {:?}", client_info);
         tokio::spawn({
-            // Disable nagle!
-            client_conn.set_nodelay(true)?;
+            // Disable nagle and apply keepalives, etc.
+            if let Err(err) = core::net::configure_socket(&client_conn, &config.get().await.tcp) {
+                log::warn!(
+                    "Failed to set socket options: {:?}, conn: {:?}",
+                    err,
+                    client_info
+                );
+            }
 
             // Build the client pgconn.
-            let mut client_conn = core::PgConn::<TcpStream>::new(client_conn)?;
+            let buffer_size = config.get().await.buffer_size();
+            let mut client_conn = core::PgConn::<TcpStream>::new(client_conn, buffer_size)?;
+            client_conn.client_addr = Some(client_addr);
 
             // Build a db pool (unique per conn for now).
             let pooler = pooler.clone();
+            let listener_config = listener_config.clone();
+            let config = config.clone();
 
             // Graceful shutdown tools.
             let shutdown = shutdown.clone();
             let worker = worker.clone();
+            let migrations = migrations.clone();
 
             // Start the show.
             async move {
                 // Retain the worker until the async block exits. This keeps it in scope.
                 let _worker = worker;
 
-                // Parse the startup flow.
-                let server_pool = match client_conn.handle_startup(pooler).await {
-                    Ok(sm) => {
+                // Parse the startup flow. Clients still at it when the shutdown
+                // deadline passes are closed.
+                let startup = tokio::select! {
+                    res = client_conn.handle_startup(pooler, &listener_config) => res,
+                    _ = core::force_shutdown(shutdown.clone()) => Err(core::admin_shutdown()),
+                };
+                let server_pool = match startup {
+                    Ok(None) => {
+                        log::trace!("Cancel request handled: {:?}", client_info);
+                        return;
+                    }
+                    Ok(Some(sm)) => {
+                        // Log the real client address from here on.
+                        if let Some(addr) = client_conn.client_addr {
+                            if addr != client_addr {
+                                client_info = format!("{} (via {})", addr, client_addr);
+                            }
+                        }
                         log::trace!(
                             "Client established and ready for query: {:?}, startup: {:?}",
                             client_info,
-                            sm
+                            client_conn.startup_message
                         );
                         sm
                     }
                     Err(err) => {
+                        client_conn.report_error(&err).await;
                         log::warn!(
                             "Client closed with error: {:?}, conn: {:?}",
                             err,
@@ -66,7 +121,7 @@ async fn listen_for_clients(
                 };
 
                 // Run the txn loop.
-                match core::spawn(client_conn, server_pool, shutdown).await {
+                match core::spawn(client_conn, server_pool, config, shutdown, migrations).await {
                     Ok(_) => println!("Client closed: {:?}", client_info),
                     Err(err) => println!(
                         "Client closed with error: {:?}, conn: {:?}",
@@ -78,6 +133,122 @@ async fn listen_for_clients(
     }
 }
 
+// Take over the idle clients the old process hands over during an upgrade,
+// until it has handed over all of them or this process shuts down.
+async fn adopt_clients(
+    stream: std::os::unix::net::UnixStream,
+    listeners: BTreeMap<String, Arc<Listener>>,
+    config: UpdatableConfig,
+    pooler: PgPooler,
+    mut shutdown: tokio::sync::watch::Receiver<String>,
+    worker: waitgroup::Worker,
+    migrations: upgrade::Migrations,
+) {
+    let stream = Arc::new(stream);
+    loop {
+        let receiving = tokio::task::spawn_blocking({
+            let stream = stream.clone();
+            move || upgrade::receive_client(&stream)
+        });
+        let (state, client_conn) = tokio::select! {
+            _ = shutdown.changed() => {
+                // Unblocks the receiving thread.
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+                return;
+            }
+            res = receiving => match res {
+                Ok(Ok(Some(client))) => client,
+                Ok(Ok(None)) => {
+                    log::info!("The old process is done handing over clients.");
+                    return;
+                }
+                Ok(Err(err)) => {
+                    log::warn!("Failed to receive client from the old process: {:?}", err);
+                    return;
+                }
+                Err(err) => {
+                    log::warn!("Failed to receive client from the old process: {:?}", err);
+                    return;
+                }
+            },
+        };
+
+        let client_info = match client_conn.peer_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => format!("{:?}", state.client_addr),
+        };
+        let listener_config = match listeners.get(&state.listener) {
+            Some(listener_config) => listener_config.clone(),
+            None => {
+                log::warn!(
+                    "Closing handed over client of a listener that isn't configured: {}, conn: {:?}",
+                    state.listener,
+                    client_info
+                );
+                continue;
+            }
+        };
+        log::info!("Client handed over: {:?}", client_info);
+
+        let client_conn = match client_conn
+            .set_nonblocking(true)
+            .and_then(|_| TcpStream::from_std(client_conn))
+        {
+            Ok(client_conn) => client_conn,
+            Err(err) => {
+                log::warn!("Failed to adopt client: {:?}, conn: {:?}", err, client_info);
+                continue;
+            }
+        };
+        if let Err(err) = core::net::configure_socket(&client_conn, &config.get().await.tcp) {
+            log::warn!(
+                "Failed to set socket options: {:?}, conn: {:?}",
+                err,
+                client_info
+            );
+        }
+        let buffer_size = config.get().await.buffer_size();
+        let mut client_conn = match core::PgConn::<TcpStream>::new(client_conn, buffer_size) {
+            Ok(client_conn) => client_conn,
+            Err(err) => {
+                log::warn!("Failed to adopt client: {:?}, conn: {:?}", err, client_info);
+                continue;
+            }
+        };
+
+        let pooler = pooler.clone();
+        let config = config.clone();
+        let shutdown = shutdown.clone();
+        let worker = worker.clone();
+        let migrations = migrations.clone();
+        tokio::spawn(async move {
+            let _worker = worker;
+
+            let server_pool = match client_conn.resume(state, pooler, &listener_config).await {
+                Ok(server_pool) => server_pool,
+                Err(err) => {
+                    client_conn.report_error(&err).await;
+                    log::warn!(
+                        "Client closed with error: {:?}, conn: {:?}",
+                        err,
+                        client_info
+                    );
+                    return;
+                }
+            };
+
+            match core::spawn(client_conn, server_pool, config, shutdown, migrations).await {
+                Ok(_) => log::info!("Client closed: {:?}", client_info),
+                Err(err) => log::warn!(
+                    "Client closed with error: {:?}, conn: {:?}",
+                    err,
+                    client_info
+                ),
+            }
+        });
+    }
+}
+
 fn say_hello() {
     log::info!(
         r#"
@@ -99,17 +270,87 @@ fn say_hello() {
     );
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
+    // This has to happen before the runtime starts any threads.
+    let listen_fds = systemd::listen_fds()?;
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(listen_fds))
+}
+
+async fn run(listen_fds: Vec<RawFd>) -> anyhow::Result<()> {
     env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
     say_hello();
 
     let opts: Opts = Opts::parse();
     let config = Config::from_file(&opts.config).await?;
 
-    let bind_addr = config.bind_address.parse::<SocketAddr>()?;
-    log::info!("Listening on: {:?}", bind_addr);
-    let listener = TcpListener::bind(bind_addr).await?;
+    // When upgrading, take over the listening sockets of the running process.
+    // Otherwise take the ones systemd passed when socket activated.
+    let mut upgrading = None;
+    let mut inherited = if opts.upgrade {
+        let path = match config.upgrade_socket {
+            Some(ref path) => path,
+            None => anyhow::bail!("--upgrade requires upgrade_socket to be configured"),
+        };
+        let (listeners, stream) = upgrade::receive_listeners(path)?;
+        upgrading = Some(stream);
+        log::info!("Took over {} listeners from {}", listeners.len(), path);
+        listeners
+    } else {
+        let listeners = systemd::listeners(&config.listeners, listen_fds)?;
+        if !listeners.is_empty() {
+            log::info!("Took over {} listeners from systemd", listeners.len());
+        }
+        listeners
+    };
+
+    // Bind every listener up front so a bad address fails at startup.
+    let mut listeners = vec![];
+    for listener_config in config.listeners.iter() {
+        let listener = match inherited.remove(&listener_config.address) {
+            Some(listener) => {
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)?
+            }
+            None => core::net::bind_listener(listener_config)?,
+        };
+        log::info!("Listening on: {:?}", listener.local_addr()?);
+        listeners.push((listener, Arc::new(listener_config.clone())));
+    }
+    if listeners.is_empty() {
+        anyhow::bail!("No listeners configured");
+    }
+    for address in inherited.keys() {
+        log::warn!(
+            "Closing inherited listener that isn't configured: {}",
+            address
+        );
+    }
+
+    // Serve the listening sockets to the next upgrade. Like the listeners, this
+    // is only read at startup.
+    let handoff = {
+        let fds = listeners
+            .iter()
+            .map(|(listener, listener_config)| {
+                (listener_config.address.clone(), listener.as_raw_fd())
+            })
+            .collect();
+        let upgrade_listener = match config.upgrade_socket {
+            Some(ref path) => Some(upgrade::bind(path)?),
+            None => None,
+        };
+        async move {
+            match upgrade_listener {
+                Some(upgrade_listener) => upgrade::serve(upgrade_listener, fds).await,
+                None => futures::future::pending().await,
+            }
+        }
+    };
+
     let config = UpdatableConfig::new(config);
     let pooler = PgPooler::new(config.clone());
 
@@ -122,6 +363,26 @@ async fn main() -> anyhow::Result<()> {
     let (tx, rx) = tokio::sync::watch::channel("".into());
     let wg = waitgroup::WaitGroup::new();
 
+    // Clients handed to the new process after an upgrade.
+    let (migrate_tx, migrate_rx) = tokio::sync::mpsc::channel(64);
+
+    // Clients handed over by the process this one upgrades.
+    if let Some(stream) = upgrading {
+        let listeners = listeners
+            .iter()
+            .map(|(_, listener_config)| (listener_config.address.clone(), listener_config.clone()))
+            .collect();
+        tokio::spawn(adopt_clients(
+            stream,
+            listeners,
+            config.clone(),
+            pooler.clone(),
+            rx.clone(),
+            wg.worker(),
+            migrate_tx.clone(),
+        ));
+    }
+
     tokio::spawn({
         let config_path = opts.config.clone();
         let config = config.clone();
@@ -131,6 +392,7 @@ async fn main() -> anyhow::Result<()> {
             loop {
                 sighup.recv().await;
                 log::warn!("Reloading config from disk...");
+                systemd::notify_reloading();
 
                 match Config::from_file(&config_path).await {
                     // Swap the config.
@@ -140,28 +402,88 @@ async fn main() -> anyhow::Result<()> {
                     }
                     Err(err) => log::warn!("Reload failed: {:?}.", err),
                 }
+                systemd::notify("READY=1");
             }
         }
     });
 
-    // Listen and await shutdown
+    tokio::spawn(stats::log_periodically(config.clone()));
+    tokio::spawn(pooler.clone().collect_idle_pools());
+
+    // Listen on every listener and await shutdown.
+    let heartbeats: Vec<_> = listeners
+        .iter()
+        .map(|_| systemd::Heartbeat::new())
+        .collect();
+    let listening = futures::future::select_all(listeners.into_iter().zip(heartbeats.clone()).map(
+        |((listener, listener_config), heartbeat)| {
+            Box::pin(listen_for_clients(
+                listener,
+                listener_config,
+                config.clone(),
+                pooler.clone(),
+                rx.clone(),
+                wg.worker(),
+                migrate_tx.clone(),
+                heartbeat,
+            ))
+        },
+    ));
+    // Clients hold the remaining senders. Migrating ends once they are gone.
+    drop(migrate_tx);
+
+    systemd::notify("READY=1");
     tokio::select! {
         _ = shutdown => {
-            // This listener is now dropped.
+            // These listeners are now dropped.
             log::warn!("Shutdown received... waiting for clients to finish transactions.");
+            systemd::notify("STOPPING=1");
             tx.send("gracefully shutdown".into())?;
         }
-        res = listen_for_clients(listener, pooler, rx.clone(), wg.worker()) => {
+        stream = handoff => {
+            // The new process accepts on the same sockets from here on, and takes
+            // over clients as they finish their transactions.
+            log::warn!("Listeners handed to a new process... handing over clients as they finish transactions.");
+            systemd::notify("STOPPING=1");
+            tokio::task::spawn_blocking(move || upgrade::migrate_clients(stream, migrate_rx));
+            tx.send(upgrade::MIGRATE_CLIENTS.into())?;
+        }
+        (res, _, _) = listening => {
             log::warn!("Listener exited: {:?}", res);
+            systemd::notify("STOPPING=1");
         }
+        // Only sent while every listener keeps beating, so systemd restarts tusq
+        // if one gets stuck or the runtime stops making progress.
+        _ = systemd::watchdog(heartbeats) => {}
     }
 
-    // Wait for shutdown or for second signal.
+    // Wait for clients to finish their transactions or for a second signal. Clients
+    // still in a transaction at the deadline are closed.
+    let shutdown_timeout = config.get().await.shutdown_timeout();
+    let deadline = async move {
+        match shutdown_timeout {
+            Some(shutdown_timeout) => tokio::time::sleep(shutdown_timeout).await,
+            None => futures::future::pending().await,
+        }
+    };
+    let mut waiting = wg.wait();
+    let mut second_signal =
+        futures::future::select(Box::pin(sigterm.recv()), Box::pin(sigint.recv()));
     tokio::select! {
-        _ = wg.wait() => { /* Successful shutdown */ }
-        _ = futures::future::select(Box::pin(sigterm.recv()), Box::pin(sigint.recv())) => {
+        _ = &mut waiting => { /* Successful shutdown */ }
+        _ = &mut second_signal => {
             log::warn!("Second shutdown signal received! Stopping now.")
         }
+        _ = deadline => {
+            log::warn!("Shutdown deadline passed! Closing clients still in a transaction.");
+            tx.send(core::FORCE_SHUTDOWN.into())?;
+            tokio::select! {
+                _ = &mut waiting => {}
+                _ = &mut second_signal => {
+                    log::warn!("Second shutdown signal received! Stopping now.")
+                }
+            }
+        }
     }
 
     log::warn!("Good bye!");
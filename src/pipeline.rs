@@ -0,0 +1,188 @@
+use crate::copy::{CopyState, CopyTracker};
+use std::collections::VecDeque;
+
+// What the server still owes the client a ReadyForQuery for, oldest first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Request {
+    // A simple Query or a FunctionCall. Each gets its own ReadyForQuery.
+    Query,
+    // Extended query messages (Parse, Bind, Execute, etc.) that were not
+    // followed by a Sync yet.
+    Unsynced,
+    Sync,
+}
+
+// Follows the requests a client sends and the responses of its server, so tusq
+// knows when nothing is in flight and the server can go back to the pool. With
+// pipelining (libpq pipeline mode, pgx batches, ...) a client can have many
+// Syncs outstanding, so a ReadyForQuery alone doesn't mean it is done.
+//
+// Feed it complete messages only, in the order they were sent.
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    requests: VecDeque<Request>,
+    // Set when an extended query failed. The server skips everything up to the
+    // next Sync, so anything queued before that gets no response.
+    is_error_recovery: bool,
+    pub copy: CopyTracker,
+}
+
+impl Pipeline {
+    pub fn client_msg(&mut self, msg_type: char) {
+        self.copy.client_msg(msg_type);
+        match msg_type {
+            'Q' | 'F' => self.requests.push_back(Request::Query),
+            // A server that is copying in ignores Syncs.
+            'S' if self.copy.is_waiting_for_client() => {}
+            'S' => self.requests.push_back(Request::Sync),
+            'P' | 'B' | 'D' | 'E' | 'C' | 'H'
+                if self.requests.back() != Some(&Request::Unsynced) =>
+            {
+                self.requests.push_back(Request::Unsynced)
+            }
+            _ => {}
+        }
+    }
+
+    pub fn server_msg(&mut self, msg_type: char) {
+        self.copy.server_msg(msg_type);
+        match msg_type {
+            'Z' => {
+                while self.requests.front() == Some(&Request::Unsynced) {
+                    self.requests.pop_front();
+                }
+                self.requests.pop_front();
+                self.is_error_recovery = false;
+            }
+            // An error in an extended query skips everything up to the Sync.
+            'E' if self.requests.front() == Some(&Request::Unsynced) => {
+                self.is_error_recovery = true;
+                while let Some(request) = self.requests.front() {
+                    if *request == Request::Sync {
+                        break;
+                    }
+                    self.requests.pop_front();
+                }
+            }
+            // A COPY started by an Execute swallows the Syncs the client already
+            // sent after it. Clients send another Sync after CopyDone.
+            'G' | 'W' if self.requests.front() == Some(&Request::Unsynced) => {
+                while self.requests.get(1) == Some(&Request::Sync) {
+                    self.requests.remove(1);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn is_error_recovery(&self) -> bool {
+        self.is_error_recovery
+    }
+
+    // Nothing is in flight: every request was answered and there is no COPY.
+    pub fn is_idle(&self) -> bool {
+        self.requests.is_empty() && self.copy.state == CopyState::Idle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(pipeline: &mut Pipeline, msg_types: &str) {
+        for msg_type in msg_types.chars() {
+            pipeline.client_msg(msg_type);
+        }
+    }
+
+    fn server(pipeline: &mut Pipeline, msg_types: &str) {
+        for msg_type in msg_types.chars() {
+            pipeline.server_msg(msg_type);
+        }
+    }
+
+    #[test]
+    fn it_waits_for_every_sync_in_a_pipeline() {
+        let mut pipeline = Pipeline::default();
+        client(&mut pipeline, "PBDES");
+        client(&mut pipeline, "BES");
+        client(&mut pipeline, "BES");
+        assert!(!pipeline.is_idle());
+
+        server(&mut pipeline, "12TDCZ");
+        server(&mut pipeline, "2DCZ");
+        assert!(!pipeline.is_idle());
+        server(&mut pipeline, "2DCZ");
+        assert!(pipeline.is_idle());
+    }
+
+    #[test]
+    fn it_waits_for_a_sync_after_a_flush() {
+        let mut pipeline = Pipeline::default();
+        client(&mut pipeline, "PBEH");
+        server(&mut pipeline, "12DC");
+        assert!(!pipeline.is_idle());
+
+        client(&mut pipeline, "S");
+        server(&mut pipeline, "Z");
+        assert!(pipeline.is_idle());
+    }
+
+    #[test]
+    fn it_waits_for_simple_queries_sent_after_a_sync() {
+        let mut pipeline = Pipeline::default();
+        client(&mut pipeline, "PBESQ");
+        server(&mut pipeline, "12CZ");
+        assert!(!pipeline.is_idle());
+        server(&mut pipeline, "TDCZ");
+        assert!(pipeline.is_idle());
+    }
+
+    #[test]
+    fn it_skips_to_the_sync_after_an_error() {
+        let mut pipeline = Pipeline::default();
+        // The Query before the Sync is skipped by the server and never answered.
+        client(&mut pipeline, "PBEQBES");
+        server(&mut pipeline, "E");
+        assert!(pipeline.is_error_recovery());
+        assert!(!pipeline.is_idle());
+
+        server(&mut pipeline, "Z");
+        assert!(!pipeline.is_error_recovery());
+        assert!(pipeline.is_idle());
+
+        // A simple query error doesn't skip anything.
+        client(&mut pipeline, "QQ");
+        server(&mut pipeline, "EZ");
+        assert!(!pipeline.is_error_recovery());
+        assert!(!pipeline.is_idle());
+        server(&mut pipeline, "CZ");
+        assert!(pipeline.is_idle());
+    }
+
+    #[test]
+    fn it_handles_an_extended_copy_from_stdin() {
+        let mut pipeline = Pipeline::default();
+        client(&mut pipeline, "PBDES");
+        server(&mut pipeline, "12nG");
+        assert!(!pipeline.is_idle());
+
+        client(&mut pipeline, "ddcS");
+        server(&mut pipeline, "C");
+        assert!(!pipeline.is_idle());
+        server(&mut pipeline, "Z");
+        assert!(pipeline.is_idle());
+    }
+
+    #[test]
+    fn it_handles_a_failed_simple_copy_from_stdin() {
+        let mut pipeline = Pipeline::default();
+        client(&mut pipeline, "Q");
+        server(&mut pipeline, "G");
+        client(&mut pipeline, "d");
+        server(&mut pipeline, "E");
+        assert!(!pipeline.is_error_recovery());
+        server(&mut pipeline, "Z");
+        assert!(pipeline.is_idle());
+    }
+}
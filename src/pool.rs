@@ -1,86 +1,107 @@
-use crate::config::UpdatableConfig;
-use crate::core::net::write_all_with_timeout;
+use crate::breaker::CircuitBreaker;
+use crate::buffer::BufferSize;
+use crate::config::{Database, TcpOptions, UpdatableConfig};
+use crate::core::net::{configure_socket, write_all_with_timeout};
 use crate::core::PgConn;
-use crate::proto::{messages, ProtoAuth, StartupMessage};
+use crate::proto::views::Backend;
+use crate::proto::{messages, ProtoAuth, StartupMessage, PROTOCOL_VERSION_3_0};
 use async_trait::async_trait;
 use bb8::{ManageConnection, Pool, PooledConnection};
 use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
+use tokio::time;
 
 #[derive(Debug)]
 pub struct PgConnPool {
     config: UpdatableConfig,
     startup_message: StartupMessage,
+    breaker: Arc<CircuitBreaker>,
+    server_parameters: Arc<ParameterCache>,
+}
+
+// The canonical server parameters for a pool, used to answer new clients
+// without checking out a server connection. Every new server connection
+// refreshes it, so it is rebuilt after a config reload.
+#[derive(Debug, Default)]
+pub struct ParameterCache {
+    inner: std::sync::Mutex<Option<(SystemTime, BTreeMap<String, String>)>>,
+}
+
+impl ParameterCache {
+    fn update(&self, parameters: BTreeMap<String, String>) {
+        let mut inner = self.inner.lock().expect("parameter cache lock");
+        *inner = Some((SystemTime::now(), parameters));
+    }
+
+    // Cached parameters, unless they were captured before `not_before`.
+    fn get(&self, not_before: SystemTime) -> Option<BTreeMap<String, String>> {
+        let inner = self.inner.lock().expect("parameter cache lock");
+        match *inner {
+            Some((updated_at, ref parameters)) if updated_at >= not_before => {
+                Some(parameters.clone())
+            }
+            _ => None,
+        }
+    }
 }
 
 impl PgConnPool {
-    pub fn new(config: UpdatableConfig, startup_message: StartupMessage) -> Self {
+    pub fn new(
+        config: UpdatableConfig,
+        startup_message: StartupMessage,
+        breaker: Arc<CircuitBreaker>,
+        server_parameters: Arc<ParameterCache>,
+    ) -> Self {
         Self {
             config,
             startup_message,
+            breaker,
+            server_parameters,
         }
     }
 
-    pub async fn pool_size(&self) -> u32 {
-        let dbname = self
-            .startup_message
-            .database_name()
-            .expect("database was set");
-
-        self.config
-            .get()
-            .await
-            .databases
-            .get(&dbname)
-            .expect("database exists")
-            .pool_size
+    pub async fn pool_size(&self) -> anyhow::Result<u32> {
+        let dbname = self.startup_message.database_name().unwrap_or_default();
+        match self.config.get().await.database(&dbname) {
+            Some(database_options) => Ok(database_options.pool_size),
+            None => anyhow::bail!("No such database: {}", dbname),
+        }
     }
-}
-
-#[async_trait]
-impl ManageConnection for PgConnPool {
-    type Connection = PgConn<TcpStream>;
-    type Error = anyhow::Error;
-
-    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        let dbname = self
-            .startup_message
-            .database_name()
-            .expect("database was set");
-
-        let database_options = {
-            self.config
-                .get()
-                .await
-                .databases
-                .get(&dbname)
-                .expect("database config to exist")
-                .clone()
-        };
 
+    // A single attempt to open and authenticate a server connection.
+    async fn connect_once(
+        &self,
+        database_options: &Database,
+        tcp_options: &TcpOptions,
+        buffer_size: BufferSize,
+        application_name: &str,
+    ) -> anyhow::Result<PgConn<TcpStream>> {
         let addr = format!("{}:{}", database_options.host, database_options.port,)
-            .parse::<SocketAddr>()
-            .expect("valid socket addr");
-
-        // Build the server startup_message.
-        let mut startup_message = self.startup_message.clone();
-        for (key, value) in database_options.startup_parameters().iter() {
-            startup_message
-                .parameters
-                .insert(key.clone(), value.clone());
-        }
+            .parse::<SocketAddr>()?;
+
+        // Build the server startup_message. Client session parameters are not
+        // passed along here; they are replayed per client on checkout.
+        let mut startup_message = StartupMessage::new();
+        // Servers always speak 3.0; tusq answers clients on newer versions itself.
+        startup_message.protocol_version = PROTOCOL_VERSION_3_0;
+        startup_message.parameters = database_options.startup_parameters();
         startup_message
             .parameters
-            .insert("application_name".into(), "tusq".into());
+            .insert("application_name".into(), application_name.into());
 
         log::info!("Connecting to database: {:?}", startup_message);
 
         let conn = TcpStream::connect(addr).await?;
-        let mut server_conn = PgConn::new(conn)?;
+        // Like on the client side, bad socket options don't fail the connect.
+        if let Err(err) = configure_socket(&conn, tcp_options) {
+            log::warn!("Failed to set socket options: {:?}, server: {}", err, addr);
+        }
+        let mut server_conn = PgConn::new(conn, buffer_size)?;
 
         // Send startup message.
         let msg = startup_message.as_bytes();
@@ -102,28 +123,29 @@ impl ManageConnection for PgConnPool {
                         match msg.authentication_type(&server_conn.buffer) {
                             Some(ProtoAuth::AuthOk) => continue,
                             Some(ProtoAuth::AuthCleartextPassword) => {
-                                let msg = messages::password_cleartext(
-                                    &database_options.password.as_ref().expect("password exists"),
-                                );
+                                let msg = messages::password_cleartext(password(database_options)?);
 
                                 write_all_with_timeout(&mut server_conn.conn, &msg, None).await?;
                             }
                             Some(ProtoAuth::AuthMD5Password(salt)) => {
                                 let msg = messages::password_md5(
                                     &database_options.user,
-                                    &database_options.password.as_ref().expect("password exists"),
+                                    password(database_options)?,
                                     salt,
                                 );
 
                                 write_all_with_timeout(&mut server_conn.conn, &msg, None).await?;
                             }
-                            None => {
-                                panic!("Auth message could not find a valid auth request (maybe a missing auth strategy?)")
-                            }
+                            None => anyhow::bail!(
+                                "Server requested an unsupported authentication method"
+                            ),
                         }
                     }
                     'Z' => {
                         if let Some('I') = msg.transaction_type(&server_conn.buffer) {
+                            server_conn.default_parameters = server_conn.server_parameters.clone();
+                            self.server_parameters
+                                .update(server_conn.default_parameters.clone());
                             return Ok(server_conn);
                         }
                     }
@@ -132,11 +154,104 @@ impl ManageConnection for PgConnPool {
                             server_conn.server_parameters.insert(key, value);
                         }
                     }
+                    'K' => {
+                        // Kept to pass on cancel requests from clients.
+                        if let Some(Backend::BackendKeyData {
+                            process_id,
+                            secret_key,
+                        }) = msg.backend(&server_conn.buffer)
+                        {
+                            server_conn.backend_key = Some((process_id, secret_key.to_vec()));
+                        }
+                    }
                     _ => { /* Ignore everything else. */ }
                 }
             }
         }
     }
+}
+
+fn password(database_options: &Database) -> anyhow::Result<&str> {
+    match database_options.password {
+        Some(ref password) => Ok(password),
+        None => anyhow::bail!(
+            "Server requested a password but none is configured for {}",
+            database_options.dbname
+        ),
+    }
+}
+
+#[async_trait]
+impl ManageConnection for PgConnPool {
+    type Connection = PgConn<TcpStream>;
+    type Error = anyhow::Error;
+
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        let dbname = self.startup_message.database_name().unwrap_or_default();
+
+        let (database_options, tcp_options, buffer_size, application_name) = {
+            let config = self.config.get().await;
+            // The database might have been removed by a config reload.
+            let database_options = match config.database(&dbname) {
+                Some(database_options) => database_options,
+                None => anyhow::bail!("No such database: {}", dbname),
+            };
+            (
+                database_options,
+                config.tcp.clone(),
+                config.buffer_size(),
+                config.application_name.connect_value(),
+            )
+        };
+
+        self.breaker.configure(
+            database_options.breaker_threshold,
+            Duration::from_millis(database_options.breaker_cooldown_ms),
+        );
+
+        let connect_timeout = Duration::from_millis(database_options.connect_timeout_ms);
+        let max_backoff = Duration::from_millis(database_options.connect_backoff_max_ms);
+        let mut backoff = Duration::from_millis(database_options.connect_backoff_ms);
+        let mut attempt = 0;
+
+        loop {
+            // Don't touch the server at all while the breaker is open.
+            if !self.breaker.try_acquire() {
+                anyhow::bail!("Circuit breaker is open for database: {}", dbname);
+            }
+
+            let attempt_conn = self.connect_once(
+                &database_options,
+                &tcp_options,
+                buffer_size,
+                &application_name,
+            );
+            let err = match time::timeout(connect_timeout, attempt_conn).await {
+                Ok(Ok(server_conn)) => {
+                    self.breaker.record_success();
+                    return Ok(server_conn);
+                }
+                Ok(Err(err)) => err,
+                Err(_) => anyhow::anyhow!("Connect timed out after {:?}", connect_timeout),
+            };
+            self.breaker.record_failure();
+
+            attempt += 1;
+            if attempt > database_options.connect_retries {
+                return Err(err);
+            }
+
+            log::warn!(
+                "Connect attempt {} to database {} failed: {:?}. Retrying in {:?}.",
+                attempt,
+                dbname,
+                err,
+                backoff
+            );
+            time::sleep(backoff).await;
+            backoff = std::cmp::min(backoff * 2, max_backoff);
+        }
+    }
 
     async fn is_valid(&self, conn: &mut PooledConnection<'_, Self>) -> Result<(), Self::Error> {
         // First check to see if the configuration has updated since we used this last.
@@ -153,10 +268,61 @@ impl ManageConnection for PgConnPool {
     }
 }
 
+// ServerPool is the handle clients use to check out server connections for a
+// single database. It wraps the bb8 pool so that clients fail fast while the
+// database's circuit breaker is open.
+#[derive(Clone, Debug)]
+pub struct ServerPool {
+    dbname: String,
+    config: UpdatableConfig,
+    pool: bb8::Pool<PgConnPool>,
+    breaker: Arc<CircuitBreaker>,
+    server_parameters: Arc<ParameterCache>,
+    // Created from the "*" template, so it is dropped when idle.
+    is_auto: bool,
+    // Last time a client held this pool. Every client holds a clone, so the
+    // strong count tells whether any are connected.
+    last_active: Arc<std::sync::Mutex<Instant>>,
+}
+
+impl ServerPool {
+    // The server parameters captured from the pool's most recent server connection,
+    // or `None` if there isn't one since the config was last loaded.
+    pub async fn cached_server_parameters(&self) -> Option<BTreeMap<String, String>> {
+        let updated_at = self.config.get().await.updated_at;
+        self.server_parameters.get(updated_at)
+    }
+
+    pub async fn get(&self) -> anyhow::Result<PooledConnection<'_, PgConnPool>> {
+        // An open breaker keeps connects from happening, so pick up settings
+        // from a config reload here too.
+        if let Some(database_options) = self.config.get().await.database(&self.dbname) {
+            self.breaker.configure(
+                database_options.breaker_threshold,
+                Duration::from_millis(database_options.breaker_cooldown_ms),
+            );
+        }
+        if self.breaker.is_open() {
+            anyhow::bail!("Circuit breaker is open for database: {}", self.dbname);
+        }
+
+        // Waiting clients give up as soon as the breaker opens.
+        tokio::select! {
+            res = self.pool.get() => res.map_err(|err| anyhow::anyhow!("Connection Pool: {:?}", err)),
+            _ = self.breaker.wait_open() => {
+                anyhow::bail!("Circuit breaker is open for database: {}", self.dbname)
+            }
+        }
+    }
+}
+
+// How often idle auto database pools are looked for.
+const AUTO_POOL_GC_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Clone)]
 pub struct PgPooler {
     config: UpdatableConfig,
-    pools: Arc<Mutex<BTreeMap<String, bb8::Pool<PgConnPool>>>>,
+    pools: Arc<Mutex<BTreeMap<String, ServerPool>>>,
 }
 
 impl PgPooler {
@@ -167,31 +333,91 @@ impl PgPooler {
         }
     }
 
+    pub fn config(&self) -> &UpdatableConfig {
+        &self.config
+    }
+
     pub async fn get_pool(
         &mut self,
         startup_message: StartupMessage,
-    ) -> anyhow::Result<bb8::Pool<PgConnPool>> {
-        // TODO: We assume the DB is always set.
-        let database = startup_message.database_name().expect("database was set");
+    ) -> anyhow::Result<ServerPool> {
+        let database = startup_message.database_name().unwrap_or_default();
 
         // Get lock around "pools", get or insert new pool, and clone.
         let mut pools = self.pools.lock().await;
-        let pool = match pools.entry(database) {
+        let pool = match pools.entry(database.clone()) {
             Entry::Occupied(pool) => pool.into_mut(),
             Entry::Vacant(pools) => {
                 // TODO: Better to unlock here while connecting? Probably? Nested locking per
                 // database?
-                // TODO: Make size params on the config.
-                let manager = PgConnPool::new(self.config.clone(), startup_message);
+                let (breaker, is_auto) = {
+                    let config = self.config.get().await;
+                    let database_options = match config.database(&database) {
+                        Some(database_options) => database_options,
+                        None => anyhow::bail!("No such database: {}", database),
+                    };
+                    let breaker = Arc::new(CircuitBreaker::new(
+                        database_options.breaker_threshold,
+                        Duration::from_millis(database_options.breaker_cooldown_ms),
+                    ));
+                    (breaker, config.is_auto_database(&database))
+                };
+                if is_auto {
+                    log::info!("Creating a pool for auto database: {}", database);
+                }
+                let server_parameters = Arc::new(ParameterCache::default());
+                let manager = PgConnPool::new(
+                    self.config.clone(),
+                    startup_message,
+                    breaker.clone(),
+                    server_parameters.clone(),
+                );
                 let pool = Pool::builder()
-                    .max_size(manager.pool_size().await)
+                    .max_size(manager.pool_size().await?)
                     .build(manager)
                     .await?;
-                pools.insert(pool)
+                pools.insert(ServerPool {
+                    dbname: database,
+                    config: self.config.clone(),
+                    pool,
+                    breaker,
+                    server_parameters,
+                    is_auto,
+                    last_active: Arc::new(std::sync::Mutex::new(Instant::now())),
+                })
             }
         }
         .clone();
 
         Ok(pool)
     }
+
+    // Drop auto database pools that haven't had a client for
+    // `auto_database_idle_timeout_ms`. Their server connections close with them.
+    pub async fn collect_idle_pools(self) {
+        loop {
+            time::sleep(AUTO_POOL_GC_INTERVAL).await;
+
+            let idle_timeout =
+                Duration::from_millis(self.config.get().await.auto_database_idle_timeout_ms);
+            let mut pools = self.pools.lock().await;
+            pools.retain(|dbname, pool| {
+                if !pool.is_auto {
+                    return true;
+                }
+
+                let mut last_active = pool.last_active.lock().expect("last active lock");
+                if Arc::strong_count(&pool.last_active) > 1 {
+                    *last_active = Instant::now();
+                    return true;
+                }
+                if last_active.elapsed() < idle_timeout {
+                    return true;
+                }
+
+                log::info!("Dropping idle pool for auto database: {}", dbname);
+                false
+            });
+        }
+    }
 }
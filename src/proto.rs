@@ -1,30 +1,87 @@
 use byteorder::{BigEndian, ByteOrder};
 use std::collections::BTreeMap;
 use std::collections::VecDeque;
+use views::{Backend, Frontend};
+
+pub mod views;
 
-// TODO: How do we make this a bit cleaner? Constants?
 pub mod messages {
+    use super::views::FieldDescription;
+    use super::CANCEL_REQUEST_VERSION;
     use byteorder::{BigEndian, ByteOrder};
 
-    pub fn password_cleartext(password: &str) -> Vec<u8> {
-        let mut msg = Vec::new();
-        msg.push(b'p');
-        // Set range aside for size at the end.
-        msg.extend_from_slice(&[0, 0, 0, 0]);
-        msg.extend_from_slice(&password.as_bytes());
-        msg.push(0);
+    // Builds a single message: the type byte, the length and then the body. The
+    // length is patched in by `finish`, so fields can be added in any size.
+    pub struct MessageBuilder {
+        msg: Vec<u8>,
+        // Where the length starts: 1 after a type byte, 0 for untyped messages.
+        length_offset: usize,
+    }
+
+    impl MessageBuilder {
+        pub fn new(msg_type: u8) -> Self {
+            Self {
+                msg: vec![msg_type, 0, 0, 0, 0],
+                length_offset: 1,
+            }
+        }
+
+        // Startup packets (StartupMessage, CancelRequest, etc.) have no type byte.
+        pub fn untyped() -> Self {
+            Self {
+                msg: vec![0, 0, 0, 0],
+                length_offset: 0,
+            }
+        }
 
-        let msg_proto_size = msg.len() - 1;
-        BigEndian::write_i32(&mut msg[1..5], msg_proto_size as i32);
-        msg
+        pub fn u8(mut self, value: u8) -> Self {
+            self.msg.push(value);
+            self
+        }
+
+        pub fn i16(mut self, value: i16) -> Self {
+            self.msg.extend_from_slice(&value.to_be_bytes());
+            self
+        }
+
+        pub fn i32(mut self, value: i32) -> Self {
+            self.msg.extend_from_slice(&value.to_be_bytes());
+            self
+        }
+
+        pub fn bytes(mut self, value: &[u8]) -> Self {
+            self.msg.extend_from_slice(value);
+            self
+        }
+
+        pub fn cstr(mut self, value: &str) -> Self {
+            self.msg.extend_from_slice(value.as_bytes());
+            self.msg.push(0);
+            self
+        }
+
+        // A length prefixed value, where `None` is sent as NULL (-1).
+        pub fn value(self, value: Option<&[u8]>) -> Self {
+            match value {
+                Some(value) => self.i32(value.len() as i32).bytes(value),
+                None => self.i32(-1),
+            }
+        }
+
+        pub fn finish(mut self) -> Vec<u8> {
+            let length = self.msg.len() - self.length_offset;
+            let offset = self.length_offset;
+            BigEndian::write_i32(&mut self.msg[offset..offset + 4], length as i32);
+            self.msg
+        }
+    }
+
+    pub fn password_cleartext(password: &str) -> Vec<u8> {
+        MessageBuilder::new(b'p').cstr(password).finish()
     }
 
     // concat('md5', md5(concat(md5(concat(password, username)), random-salt)))
     pub fn password_md5(username: &str, password: &str, salt: &[u8]) -> Vec<u8> {
-        let mut msg = Vec::new();
-        msg.push(b'p');
-        // Set range aside for size at the end.
-        msg.extend_from_slice(&[0, 0, 0, 0]);
         // concat(password, username)
         let userpass = format!("{}{}", password, username);
         // md5(ABOVE)
@@ -33,47 +90,184 @@ pub mod messages {
         let md5: Vec<_> = md5.bytes().chain(salt.iter().copied()).collect();
         // concat('md5', md5(ABOVE))
         let md5 = format!("md5{:x}", md5::compute(&md5));
-        msg.extend_from_slice(&md5.as_bytes());
-        msg.push(0);
-
-        let msg_proto_size = msg.len() - 1;
-        BigEndian::write_i32(&mut msg[1..5], msg_proto_size as i32);
-        msg
+        MessageBuilder::new(b'p').cstr(&md5).finish()
     }
 
     pub fn auth_ok() -> Vec<u8> {
-        let mut msg = [0; 9];
-        msg[0] = b'R';
-        BigEndian::write_i32(&mut msg[1..5], 8);
-        BigEndian::write_i32(&mut msg[5..9], 0);
-        msg.into()
+        MessageBuilder::new(b'R').i32(0).finish()
+    }
+
+    // AuthenticationSASL: the mechanisms the server supports, e.g. SCRAM-SHA-256.
+    pub fn auth_sasl(mechanisms: &[&str]) -> Vec<u8> {
+        let mut msg = MessageBuilder::new(b'R').i32(10);
+        for mechanism in mechanisms.iter() {
+            msg = msg.cstr(mechanism);
+        }
+        msg.u8(0).finish()
+    }
+
+    pub fn auth_sasl_continue(data: &[u8]) -> Vec<u8> {
+        MessageBuilder::new(b'R').i32(11).bytes(data).finish()
+    }
+
+    pub fn auth_sasl_final(data: &[u8]) -> Vec<u8> {
+        MessageBuilder::new(b'R').i32(12).bytes(data).finish()
+    }
+
+    // SASLInitialResponse: the chosen mechanism and the optional first message.
+    pub fn sasl_initial_response(mechanism: &str, data: Option<&[u8]>) -> Vec<u8> {
+        MessageBuilder::new(b'p')
+            .cstr(mechanism)
+            .value(data)
+            .finish()
+    }
+
+    pub fn sasl_response(data: &[u8]) -> Vec<u8> {
+        MessageBuilder::new(b'p').bytes(data).finish()
     }
 
-    pub fn ready_for_query() -> Vec<u8> {
-        let mut msg = [0; 6];
-        msg[0] = b'Z';
-        BigEndian::write_i32(&mut msg[1..5], 5);
-        msg[5] = b'I';
-        msg.into()
+    // The status is 'I' (idle), 'T' (in a transaction) or 'E' (in a failed transaction).
+    pub fn ready_for_query(status: char) -> Vec<u8> {
+        MessageBuilder::new(b'Z').u8(status as u8).finish()
     }
 
     pub fn server_parameter(key: &str, value: &str) -> Vec<u8> {
-        let mut msg = Vec::new();
-        msg.push(b'S');
-        // Set range aside for size at the end.
-        msg.extend_from_slice(&[0, 0, 0, 0]);
-        msg.extend_from_slice(key.as_bytes());
-        msg.push(0);
-        msg.extend_from_slice(value.as_bytes());
-        msg.push(0);
-        let msg_proto_size = msg.len() - 1;
-        BigEndian::write_i32(&mut msg[1..5], msg_proto_size as i32);
-        msg
+        MessageBuilder::new(b'S').cstr(key).cstr(value).finish()
+    }
+
+    // The secret key is 4 bytes long in protocol 3.0 and 32 bytes in 3.2.
+    pub fn backend_key_data(process_id: i32, secret_key: &[u8]) -> Vec<u8> {
+        MessageBuilder::new(b'K')
+            .i32(process_id)
+            .bytes(secret_key)
+            .finish()
+    }
+
+    pub fn cancel_request(process_id: i32, secret_key: &[u8]) -> Vec<u8> {
+        MessageBuilder::untyped()
+            .i32(CANCEL_REQUEST_VERSION)
+            .i32(process_id)
+            .bytes(secret_key)
+            .finish()
+    }
+
+    // Sent instead of failing when a client asks for a newer minor version or for
+    // protocol options (`_pq_.` parameters) tusq doesn't know.
+    pub fn negotiate_protocol_version(minor: i32, options: &[&str]) -> Vec<u8> {
+        let mut msg = MessageBuilder::new(b'v')
+            .i32(minor)
+            .i32(options.len() as i32);
+        for option in options.iter() {
+            msg = msg.cstr(option);
+        }
+        msg.finish()
+    }
+
+    // Simple Query ('Q') message.
+    pub fn query(sql: &str) -> Vec<u8> {
+        MessageBuilder::new(b'Q').cstr(sql).finish()
+    }
+
+    // Sync ('S') ends an extended query batch.
+    pub fn sync() -> Vec<u8> {
+        MessageBuilder::new(b'S').finish()
+    }
+
+    // CopyFail ('f') aborts a COPY FROM STDIN with an error message.
+    pub fn copy_fail(message: &str) -> Vec<u8> {
+        MessageBuilder::new(b'f').cstr(message).finish()
+    }
+
+    pub fn row_description(fields: &[FieldDescription]) -> Vec<u8> {
+        let mut msg = MessageBuilder::new(b'T').i16(fields.len() as i16);
+        for field in fields.iter() {
+            msg = msg
+                .cstr(field.name)
+                .i32(field.table_oid)
+                .i16(field.column)
+                .i32(field.type_oid)
+                .i16(field.type_size)
+                .i32(field.type_modifier)
+                .i16(field.format);
+        }
+        msg.finish()
+    }
+
+    pub fn data_row(values: &[Option<&[u8]>]) -> Vec<u8> {
+        let mut msg = MessageBuilder::new(b'D').i16(values.len() as i16);
+        for value in values.iter() {
+            msg = msg.value(*value);
+        }
+        msg.finish()
+    }
+
+    // The tag is the command name and row count, e.g. "SELECT 1" or "INSERT 0 1".
+    pub fn command_complete(tag: &str) -> Vec<u8> {
+        MessageBuilder::new(b'C').cstr(tag).finish()
+    }
+
+    pub fn empty_query_response() -> Vec<u8> {
+        MessageBuilder::new(b'I').finish()
+    }
+
+    // ErrorResponse with the minimum fields libpq expects: severity, SQLSTATE
+    // code and a human readable message.
+    pub fn error_response(severity: &str, code: &str, message: &str) -> Vec<u8> {
+        notice_fields(
+            b'E',
+            &[
+                ('S', severity),
+                ('V', severity),
+                ('C', code),
+                ('M', message),
+            ],
+        )
+    }
+
+    pub fn notice_response(severity: &str, code: &str, message: &str) -> Vec<u8> {
+        notice_fields(
+            b'N',
+            &[
+                ('S', severity),
+                ('V', severity),
+                ('C', code),
+                ('M', message),
+            ],
+        )
+    }
+
+    // An ErrorResponse ('E') or NoticeResponse ('N') with any fields, e.g. a 'D'
+    // detail or an 'H' hint.
+    pub fn notice_fields(msg_type: u8, fields: &[(char, &str)]) -> Vec<u8> {
+        let mut msg = MessageBuilder::new(msg_type);
+        for (field_type, value) in fields.iter() {
+            msg = msg.u8(*field_type as u8).cstr(value);
+        }
+        // Terminating null byte.
+        msg.u8(0).finish()
     }
 
     #[cfg(test)]
     mod test {
+        use super::super::views::{Backend, Frontend};
+        use super::super::{ProtoMessage, ProtoParser, ProtoStartup};
         use super::*;
+        use std::collections::VecDeque;
+
+        #[test]
+        fn it_can_create_a_query() {
+            let expected = &[81, 0, 0, 0, 13, 83, 69, 76, 69, 67, 84, 32, 49, 0];
+            assert_eq!(&query("SELECT 1"), expected);
+        }
+
+        #[test]
+        fn it_can_create_an_error_response() {
+            let expected = &[
+                69, 0, 0, 0, 32, 83, 69, 82, 82, 79, 82, 0, 86, 69, 82, 82, 79, 82, 0, 67, 48, 56,
+                48, 48, 54, 0, 77, 111, 111, 112, 115, 0, 0,
+            ];
+            assert_eq!(&error_response("ERROR", "08006", "oops"), expected);
+        }
 
         #[test]
         fn it_can_create_a_cleartext_password_response() {
@@ -96,6 +290,183 @@ pub mod messages {
             ];
             assert_eq!(&password_md5(user, password, salt), expected);
         }
+
+        // Parse a single encoded message and return its body.
+        fn parse_one(msg: &[u8]) -> (char, &[u8]) {
+            let mut msgs = VecDeque::new();
+            let n = ProtoParser::new().parse(msg, &mut msgs).unwrap();
+            assert_eq!(n, msg.len());
+            assert_eq!(msgs.len(), 1);
+            assert_eq!(
+                msgs[0],
+                ProtoMessage::Message(msgs[0].msg_type(), 0, msg.len() - 1)
+            );
+            (msgs[0].msg_type(), msgs[0].body(msg).unwrap())
+        }
+
+        fn round_trip(msg: &[u8]) -> Backend<'_> {
+            let (msg_type, body) = parse_one(msg);
+            Backend::parse(msg_type, body).unwrap()
+        }
+
+        #[test]
+        fn it_round_trips_backend_messages() {
+            let fields = vec![FieldDescription {
+                name: "id",
+                table_oid: 16384,
+                column: 1,
+                type_oid: 23,
+                type_size: 4,
+                type_modifier: -1,
+                format: 0,
+            }];
+            assert_eq!(
+                round_trip(&row_description(&fields)),
+                Backend::RowDescription { fields }
+            );
+
+            let values = vec![Some(&b"1"[..]), None, Some(&b""[..])];
+            assert_eq!(round_trip(&data_row(&values)), Backend::DataRow { values });
+
+            assert_eq!(
+                round_trip(&command_complete("SELECT 1")),
+                Backend::CommandComplete { tag: "SELECT 1" }
+            );
+            assert_eq!(
+                round_trip(&backend_key_data(42, &[1, 2, 3, 4])),
+                Backend::BackendKeyData {
+                    process_id: 42,
+                    secret_key: &[1, 2, 3, 4],
+                }
+            );
+            assert_eq!(
+                round_trip(&empty_query_response()),
+                Backend::EmptyQueryResponse
+            );
+            for status in ['I', 'T', 'E'] {
+                assert_eq!(
+                    round_trip(&ready_for_query(status)),
+                    Backend::ReadyForQuery { status }
+                );
+            }
+            assert_eq!(
+                round_trip(&server_parameter("TimeZone", "UTC")),
+                Backend::ParameterStatus {
+                    name: "TimeZone",
+                    value: "UTC",
+                }
+            );
+        }
+
+        #[test]
+        fn it_round_trips_error_and_notice_responses() {
+            match round_trip(&error_response("FATAL", "57P01", "bye")) {
+                Backend::ErrorResponse(fields) => {
+                    assert_eq!(fields.severity(), Some("FATAL"));
+                    assert_eq!(fields.code(), Some("57P01"));
+                    assert_eq!(fields.message(), Some("bye"));
+                }
+                view => panic!("unexpected view: {:?}", view),
+            }
+
+            let msg = notice_fields(b'N', &[('S', "WARNING"), ('M', "careful"), ('H', "hint")]);
+            match round_trip(&msg) {
+                Backend::NoticeResponse(fields) => {
+                    assert_eq!(fields.severity(), Some("WARNING"));
+                    assert_eq!(fields.message(), Some("careful"));
+                    assert_eq!(fields.get('H'), Some("hint"));
+                }
+                view => panic!("unexpected view: {:?}", view),
+            }
+            assert!(matches!(
+                round_trip(&notice_response("NOTICE", "00000", "hi")),
+                Backend::NoticeResponse(_)
+            ));
+        }
+
+        #[test]
+        fn it_round_trips_sasl_messages() {
+            assert_eq!(
+                round_trip(&auth_sasl(&["SCRAM-SHA-256", "SCRAM-SHA-256-PLUS"])),
+                Backend::Authentication {
+                    code: 10,
+                    data: b"SCRAM-SHA-256\0SCRAM-SHA-256-PLUS\0\0",
+                }
+            );
+            assert_eq!(
+                round_trip(&auth_sasl_continue(b"r=abc")),
+                Backend::Authentication {
+                    code: 11,
+                    data: b"r=abc",
+                }
+            );
+            assert_eq!(
+                round_trip(&auth_sasl_final(b"v=xyz")),
+                Backend::Authentication {
+                    code: 12,
+                    data: b"v=xyz",
+                }
+            );
+
+            let msg = sasl_initial_response("SCRAM-SHA-256", Some(b"n,,n=,r=abc"));
+            let (msg_type, body) = parse_one(&msg);
+            assert_eq!(msg_type, 'p');
+            assert_eq!(body, b"SCRAM-SHA-256\0\0\0\0\x0bn,,n=,r=abc");
+
+            let msg = sasl_response(b"c=biws");
+            assert_eq!(parse_one(&msg), ('p', &b"c=biws"[..]));
+        }
+
+        #[test]
+        fn it_round_trips_sync_and_copy_fail() {
+            assert_eq!(parse_one(&sync()), ('S', &b""[..]));
+
+            let msg = copy_fail("nope");
+            let (msg_type, body) = parse_one(&msg);
+            assert_eq!(
+                Frontend::parse(msg_type, body).unwrap(),
+                Frontend::CopyFail { message: "nope" }
+            );
+        }
+
+        #[test]
+        fn it_round_trips_a_cancel_request() {
+            let msg = cancel_request(42, &[1, 2, 3, 4]);
+            assert_eq!(msg.len(), 16);
+
+            let (n, startup) = ProtoParser::new().parse_startup(&msg).unwrap();
+            assert_eq!(n, 16);
+            assert_eq!(
+                startup,
+                Some(ProtoStartup::CancelRequest {
+                    process_id: 42,
+                    secret_key: vec![1, 2, 3, 4],
+                })
+            );
+
+            // Protocol 3.2 keys are longer.
+            let msg = cancel_request(42, &[7; 32]);
+            let (_, startup) = ProtoParser::new().parse_startup(&msg).unwrap();
+            assert_eq!(
+                startup,
+                Some(ProtoStartup::CancelRequest {
+                    process_id: 42,
+                    secret_key: vec![7; 32],
+                })
+            );
+        }
+
+        #[test]
+        fn it_round_trips_negotiate_protocol_version() {
+            let msg = negotiate_protocol_version(2, &["_pq_.foo", "_pq_.bar"]);
+            assert_eq!(
+                Backend::parse('v', &msg[5..]).unwrap(),
+                Backend::NegotiateProtocolVersion {
+                    minor: 2,
+                    options: vec!["_pq_.foo", "_pq_.bar"],
+                }
+            );
+        }
     }
 }
 
@@ -111,14 +482,28 @@ pub struct ProtoParser {
     current_msg_length: usize,
     // Bytes read of the current message (might take multiple buffers).
     current_msg_bytes_read: usize,
-    // Startup message if one is being parsed.
-    current_startup_message: Option<StartupMessage>,
-    current_startup_parameter_key: Option<String>,
-    current_startup_parameter_value: Option<String>,
+    // The startup packet read so far, including its length.
+    startup_buffer: Vec<u8>,
+}
+
+impl Default for ProtoParser {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 const CANCEL_REQUEST_VERSION: i32 = 80877102;
 const SSL_REQUEST_VERSION: i32 = 80877103;
+const GSSENC_REQUEST_VERSION: i32 = 80877104;
+pub const PROTOCOL_VERSION_3_0: i32 = 3 << 16;
+pub const PROTOCOL_VERSION_3_2: i32 = 3 << 16 | 2;
+// Protocol options are startup parameters with this prefix.
+const PROTOCOL_OPTION_PREFIX: &str = "_pq_.";
+// A CancelRequest is 12 bytes plus a secret key of up to 256 bytes.
+const MAX_CANCEL_REQUEST_SIZE: usize = 12 + 256;
+// Same limit as postgres (MAX_STARTUP_PACKET_LENGTH), which is far more than
+// any real client sends.
+pub const MAX_STARTUP_PACKET_SIZE: usize = 10_000;
 
 impl ProtoParser {
     pub fn new() -> Self {
@@ -126,9 +511,7 @@ impl ProtoParser {
             current_msg_type: None,
             current_msg_length: 0,
             current_msg_bytes_read: 0,
-            current_startup_message: None,
-            current_startup_parameter_key: None,
-            current_startup_parameter_value: None,
+            startup_buffer: Vec::new(),
         }
     }
 
@@ -140,125 +523,114 @@ impl ProtoParser {
         Some(BigEndian::read_i32(&buffer[0..4]) as usize)
     }
 
-    // This will parse a StartupMessage or SSLRequest using one or more buffers.
-    // Unlike the `parse` method, this will copy data in that buffer to create
-    // a shareable startup message.
+    // This will parse a StartupMessage, SSLRequest, GSSENCRequest or CancelRequest
+    // fed in any number of pieces, down to a byte at a time. Bytes are consumed up
+    // to the end of the packet and the packet is returned once it is complete.
+    // Unlike the `parse` method, this copies the packet, so the buffer can be
+    // reused. Malformed packets are an error, after which the parser starts over.
     pub fn parse_startup(
         &mut self,
         buffer: &[u8],
     ) -> anyhow::Result<(usize, Option<ProtoStartup>)> {
-        let mut offset = 0;
-
-        // If we don't have a current startup message, be sure to parse
-        // the size and allocate a new struct.
-        if self.current_startup_message.is_none() {
-            // First 4 bytes tell us the size of the startup message.
-            if buffer.len() < 4 {
-                return Ok((0, None));
-            }
-            self.current_msg_length = BigEndian::read_i32(&buffer[0..4]) as usize;
-            self.current_startup_message = Some(StartupMessage::new());
-            offset += 4;
-            self.current_msg_bytes_read += 4;
-        }
-
-        if let Some(ref mut startup_message) = self.current_startup_message {
-            // Check if we need to parse the protocol version.
-            if self.current_msg_bytes_read < 8 {
-                let bytes_to_read = std::cmp::min(4, buffer.len() - offset);
-                if bytes_to_read < 4 {
-                    return Ok((offset, None));
-                }
+        let res = self.parse_startup_bytes(buffer);
+        if res.is_err() {
+            self.startup_buffer.clear();
+        }
+        res
+    }
 
-                startup_message.protocol_version = BigEndian::read_i32(&buffer[offset..offset + 4]);
-                offset += 4;
-                self.current_msg_bytes_read += 4;
-            }
+    fn parse_startup_bytes(
+        &mut self,
+        buffer: &[u8],
+    ) -> anyhow::Result<(usize, Option<ProtoStartup>)> {
+        let mut offset = 0;
 
-            // Detect if this is a Cancel Request
-            if startup_message.protocol_version == CANCEL_REQUEST_VERSION
-                && self.current_msg_length == 16
-            {
-                self.msg_complete();
-                self.current_startup_message = None;
-                return Ok((16, Some(ProtoStartup::CancelRequest)));
+        // First 4 bytes tell us the size of the startup packet.
+        if self.startup_buffer.len() < 4 {
+            let n = std::cmp::min(4 - self.startup_buffer.len(), buffer.len());
+            self.startup_buffer.extend_from_slice(&buffer[..n]);
+            offset += n;
+            if self.startup_buffer.len() < 4 {
+                return Ok((offset, None));
             }
+        }
 
-            // Detect if this is an SSL Request
-            if startup_message.protocol_version == SSL_REQUEST_VERSION
-                && self.current_msg_length == 8
-            {
-                self.msg_complete();
-                self.current_startup_message = None;
-                return Ok((8, Some(ProtoStartup::SSLRequest)));
-            }
+        let length = BigEndian::read_i32(&self.startup_buffer[0..4]);
+        if length < 8 || length as usize > MAX_STARTUP_PACKET_SIZE {
+            anyhow::bail!("invalid length of startup packet: {}", length);
+        }
 
-            loop {
-                // Check for parameter termination.
-                if buffer[offset] == 0 {
-                    // Reset counters.
-                    self.msg_complete();
-                    return Ok((
-                        offset + 1,
-                        self.current_startup_message
-                            .take()
-                            .map(ProtoStartup::Message),
-                    ));
-                }
+        // Copy up to the end of the packet and wait for more if it isn't there yet.
+        let remaining = length as usize - self.startup_buffer.len();
+        let n = std::cmp::min(remaining, buffer.len() - offset);
+        self.startup_buffer
+            .extend_from_slice(&buffer[offset..offset + n]);
+        offset += n;
+        if n < remaining {
+            return Ok((offset, None));
+        }
 
-                // Otherwise we are parsing parameters.
-                // TODO: There is some state tracking so we can essentially use 1-byte
-                // buffers after reading the first 4-byte msg size, but I don't feel
-                // like writing that at the moment.
-
-                // Parse the key...
-                if self.current_startup_parameter_key.is_none() {
-                    let pos = memchr::memchr(0, &buffer[offset..])
-                        .expect("no support for partial cstr reads for now");
-
-                    // Parse the entire valid cstr and move forward.
-                    let cstr = &buffer[offset..offset + pos];
-                    self.current_startup_parameter_key = Some(
-                        std::str::from_utf8(&cstr)
-                            .expect("todo: add error handling")
-                            .into(),
-                    );
-
-                    offset += pos + 1;
-                    self.current_msg_bytes_read += pos + 1;
-                }
+        let packet = std::mem::take(&mut self.startup_buffer);
+        Ok((offset, Some(Self::decode_startup(&packet)?)))
+    }
 
-                // Parse the value...
-                if self.current_startup_parameter_value.is_none() {
-                    let pos = memchr::memchr(0, &buffer[offset..])
-                        .expect("no support for partial cstr reads for now");
-
-                    // Parse the entire valid cstr and move forward.
-                    let cstr = &buffer[offset..offset + pos];
-                    self.current_startup_parameter_value = Some(
-                        std::str::from_utf8(&cstr)
-                            .expect("todo: add error handling")
-                            .into(),
-                    );
-
-                    offset += pos + 1;
-                    self.current_msg_bytes_read += pos + 1;
-                }
+    // Decode a complete startup packet, including its length.
+    fn decode_startup(packet: &[u8]) -> anyhow::Result<ProtoStartup> {
+        let protocol_version = BigEndian::read_i32(&packet[4..8]);
+        match (protocol_version, packet.len()) {
+            (CANCEL_REQUEST_VERSION, 16..=MAX_CANCEL_REQUEST_SIZE) => {
+                return Ok(ProtoStartup::CancelRequest {
+                    process_id: BigEndian::read_i32(&packet[8..12]),
+                    secret_key: packet[12..].to_vec(),
+                })
+            }
+            (SSL_REQUEST_VERSION, 8) => return Ok(ProtoStartup::SSLRequest),
+            (GSSENC_REQUEST_VERSION, 8) => return Ok(ProtoStartup::GSSENCRequest),
+            (CANCEL_REQUEST_VERSION, _)
+            | (SSL_REQUEST_VERSION, _)
+            | (GSSENC_REQUEST_VERSION, _) => {
+                anyhow::bail!("invalid length of startup packet: {}", packet.len())
+            }
+            _ => {}
+        }
+        if protocol_version >> 16 != 3 {
+            anyhow::bail!(
+                "unsupported frontend protocol {}.{}",
+                protocol_version >> 16,
+                protocol_version & 0xFFFF
+            );
+        }
 
-                // Store parameter and reset startup param variables.
-                startup_message.parameters.insert(
-                    self.current_startup_parameter_key
-                        .take()
-                        .expect("checked above"),
-                    self.current_startup_parameter_value
-                        .take()
-                        .expect("checked above"),
-                );
+        // Key/value cstr pairs, terminated by an empty key.
+        let mut startup_message = StartupMessage::new();
+        startup_message.protocol_version = protocol_version;
+        let mut params = &packet[8..];
+        loop {
+            let key = Self::startup_cstr(&mut params)?;
+            if key.is_empty() {
+                break;
             }
+            let value = Self::startup_cstr(&mut params)?;
+            startup_message.parameters.insert(key, value);
+        }
+        if !params.is_empty() {
+            anyhow::bail!("invalid startup packet layout: expected terminator as last byte");
         }
 
-        // Then begin parsing with available buffer.
-        Ok((0, None))
+        Ok(ProtoStartup::Message(startup_message))
+    }
+
+    fn startup_cstr(params: &mut &[u8]) -> anyhow::Result<String> {
+        let end = match memchr::memchr(0, params) {
+            Some(end) => end,
+            None => anyhow::bail!("invalid startup packet layout: missing terminator"),
+        };
+        let cstr = match std::str::from_utf8(&params[..end]) {
+            Ok(cstr) => cstr.to_string(),
+            Err(_) => anyhow::bail!("invalid startup packet: parameters must be valid UTF-8"),
+        };
+        *params = &params[end + 1..];
+        Ok(cstr)
     }
 
     // The caller is expected to use a buffer range that was not previously
@@ -319,9 +691,15 @@ impl ProtoParser {
             }
 
             // Expect and handle new message.
-            self.current_msg_type = Some(buffer[offset] as char);
+            let msg_type = buffer[offset] as char;
             offset += 1;
-            self.current_msg_length = BigEndian::read_i32(&buffer[offset..offset + 4]) as usize;
+            // The length counts itself, so anything under 4 can't be right.
+            let length = BigEndian::read_i32(&buffer[offset..offset + 4]);
+            if length < 4 {
+                anyhow::bail!("invalid length of {:?} message: {}", msg_type, length);
+            }
+            self.current_msg_type = Some(msg_type);
+            self.current_msg_length = length as usize;
 
             let remaining = self.current_msg_length - self.current_msg_bytes_read;
             let bytes_to_read = std::cmp::min(buffer.len() - offset, remaining);
@@ -351,6 +729,31 @@ impl ProtoParser {
         Ok(offset)
     }
 
+    // The type of the message the last parse ended in the middle of, and the
+    // number of its bytes still to come.
+    pub fn partial_remaining(&self) -> Option<(char, usize)> {
+        let msg_type = self.current_msg_type?;
+        Some((
+            msg_type,
+            self.current_msg_length - self.current_msg_bytes_read,
+        ))
+    }
+
+    // Account for bytes of the current message that were passed along without
+    // being parsed, e.g. with splice(2). The next parse starts after them.
+    pub fn skip(&mut self, n: usize) -> anyhow::Result<()> {
+        match self.partial_remaining() {
+            Some((_, remaining)) if n <= remaining => {
+                self.current_msg_bytes_read += n;
+                if n == remaining {
+                    self.msg_complete();
+                }
+                Ok(())
+            }
+            _ => anyhow::bail!("Can't skip {} bytes past the current message", n),
+        }
+    }
+
     fn msg_complete(&mut self) {
         self.current_msg_type = None;
         self.current_msg_length = 0;
@@ -377,100 +780,104 @@ pub enum ProtoMessage {
 }
 
 impl ProtoMessage {
-    // TODO: Make this work with a Partial + PartialComplete.
-    pub fn error_message(&self, buffer: &[u8]) -> anyhow::Result<Option<String>> {
-        if let ProtoMessage::Message('E', start, end) = self {
-            if end - start < 6 {
-                return Ok(None);
+    // The body of a complete message, without the type and length header. Pieces
+    // of a split message can be put back together with `views::Assembler`.
+    pub fn body<'a>(&self, buffer: &'a [u8]) -> Option<&'a [u8]> {
+        match *self {
+            ProtoMessage::Message(_, start, end) => buffer.get(start + 5..=end),
+            _ => None,
+        }
+    }
+
+    // A typed view of a complete message sent by a server.
+    pub fn backend<'a>(&self, buffer: &'a [u8]) -> Option<Backend<'a>> {
+        let body = self.body(buffer)?;
+        match Backend::parse(self.msg_type(), body) {
+            Ok(view) => Some(view),
+            Err(err) => {
+                log::trace!("Invalid {:?} message from server: {:?}", self, err);
+                None
             }
-            if buffer[start + 6] == 0 {
-                // Empty string error.
-                return Ok(Some("".to_string()));
+        }
+    }
+
+    // A typed view of a complete message sent by a client.
+    pub fn frontend<'a>(&self, buffer: &'a [u8]) -> Option<Frontend<'a>> {
+        let body = self.body(buffer)?;
+        match Frontend::parse(self.msg_type(), body) {
+            Ok(view) => Some(view),
+            Err(err) => {
+                log::trace!("Invalid {:?} message from client: {:?}", self, err);
+                None
             }
+        }
+    }
 
-            // Convert the buffer error string into a String.
-            let msg = std::str::from_utf8(&buffer[start + 7..*end])?.to_string();
-            return Ok(Some(msg));
+    pub fn error_message(&self, buffer: &[u8]) -> anyhow::Result<Option<String>> {
+        if self.msg_type() != 'E' {
+            return Ok(None);
+        }
+        match self.body(buffer).map(|body| Backend::parse('E', body)) {
+            Some(Ok(Backend::ErrorResponse(fields))) => {
+                Ok(Some(fields.message().unwrap_or_default().to_string()))
+            }
+            Some(Err(err)) => Err(err),
+            _ => Ok(None),
         }
-        Ok(None)
     }
 
-    // TODO: Make this work with a Partial + PartialComplete.
     pub fn authentication_type<'a>(&self, buffer: &'a [u8]) -> Option<ProtoAuth<'a>> {
-        if let ProtoMessage::Message('R', start, end) = self {
-            if end - start < 8 {
-                return None;
+        match self.backend(buffer)? {
+            Backend::Authentication { code: 0, .. } => Some(ProtoAuth::AuthOk),
+            Backend::Authentication { code: 3, .. } => Some(ProtoAuth::AuthCleartextPassword),
+            Backend::Authentication { code: 5, data } if data.len() >= 4 => {
+                Some(ProtoAuth::AuthMD5Password(&data[..4]))
             }
-            let auth_type = BigEndian::read_i32(&buffer[start + 5..start + 10]);
-
-            return match auth_type {
-                0 => Some(ProtoAuth::AuthOk),
-                3 => Some(ProtoAuth::AuthCleartextPassword),
-                5 => {
-                    // Check for additional buffer size.
-                    if buffer.len() <= start + 13 {
-                        return None;
-                    }
-                    Some(ProtoAuth::AuthMD5Password(&buffer[start + 9..start + 13]))
-                }
-                _ => {
-                    log::trace!("Missing authentication type code: {}", auth_type);
-                    None
-                }
-            };
+            Backend::Authentication { code, .. } => {
+                log::trace!("Missing authentication type code: {}", code);
+                None
+            }
+            _ => None,
         }
-        None
     }
 
     // Pull the txn type from a ready for query message.
-    // TODO: Make this work with a Partial + PartialComplete.
     pub fn transaction_type(&self, buffer: &[u8]) -> Option<char> {
-        if let ProtoMessage::Message('Z', start, end) = self {
-            if end - start == 5 {
-                return Some(buffer[start + 5] as char);
-            }
+        match self.backend(buffer)? {
+            Backend::ReadyForQuery { status } => Some(status),
+            _ => None,
         }
-        None
     }
 
     pub fn server_parameter(&self, buffer: &[u8]) -> Option<(String, String)> {
-        if let ProtoMessage::Message('S', start, _end) = self {
-            // TODO: Make this safer. For now this assumes the message is valid.
-            // Skip the first 5 bytes for [char, i32] and move on to String.
-            let mut offset = 5 + start;
-
-            let key_end = memchr::memchr(0, &buffer[offset..])
-                .expect("no support for partial cstr reads for now");
-            let key = std::str::from_utf8(&buffer[offset..offset + key_end])
-                .expect("valid utf8")
-                .into();
-
-            // Update offset.
-            offset += key_end + 1;
-
-            let value_end = memchr::memchr(0, &buffer[offset..])
-                .expect("no support for partial cstr reads for now");
-            let value = std::str::from_utf8(&buffer[offset..offset + value_end])
-                .expect("valid utf8")
-                .into();
-
-            return Some((key, value));
+        match self.backend(buffer)? {
+            Backend::ParameterStatus { name, value } => Some((name.into(), value.into())),
+            _ => None,
         }
-        None
     }
 
     pub fn is_complete(&self) -> bool {
-        if let ProtoMessage::Message(_, _, _) = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, ProtoMessage::Message(_, _, _))
     }
 
     pub fn is_partial(&self) -> bool {
         !self.is_complete()
     }
 
+    // This is a complete message or the last piece of a split one.
+    pub fn ends_message(&self) -> bool {
+        !matches!(self, ProtoMessage::Partial(_, _, _))
+    }
+
+    // The number of bytes of this message (or piece of it) in the buffer.
+    pub fn size(&self) -> usize {
+        match *self {
+            ProtoMessage::Message(_, start, end) => end - start + 1,
+            ProtoMessage::Partial(_, start, end) => end - start + 1,
+            ProtoMessage::PartialComplete(_, end) => end + 1,
+        }
+    }
+
     pub fn msg_type(&self) -> char {
         *match self {
             ProtoMessage::Message(msg_type, _, _) => msg_type,
@@ -490,7 +897,11 @@ pub enum ProtoStartupMessage {
 pub enum ProtoStartup {
     Message(StartupMessage),
     SSLRequest,
-    CancelRequest,
+    GSSENCRequest,
+    CancelRequest {
+        process_id: i32,
+        secret_key: Vec<u8>,
+    },
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -499,6 +910,12 @@ pub struct StartupMessage {
     pub parameters: BTreeMap<String, String>,
 }
 
+impl Default for StartupMessage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl StartupMessage {
     pub fn new() -> Self {
         Self {
@@ -508,38 +925,54 @@ impl StartupMessage {
     }
 
     pub fn database_name(&self) -> Option<String> {
-        match self.parameters.get("database") {
-            Some(value) => Some(value.clone()),
-            None => None,
+        self.parameters.get("database").cloned()
+    }
+
+    // Settle on a protocol version tusq supports (3.0 through 3.2) and drop any
+    // protocol options, since tusq doesn't support any. When the client asked
+    // for more than that, the NegotiateProtocolVersion to send back is returned.
+    pub fn negotiate_protocol_version(&mut self) -> Option<Vec<u8>> {
+        let options: Vec<String> = self
+            .parameters
+            .keys()
+            .filter(|key| key.starts_with(PROTOCOL_OPTION_PREFIX))
+            .cloned()
+            .collect();
+        for option in options.iter() {
+            self.parameters.remove(option);
+        }
+
+        if self.protocol_version <= PROTOCOL_VERSION_3_2 && options.is_empty() {
+            return None;
+        }
+        self.protocol_version = std::cmp::min(self.protocol_version, PROTOCOL_VERSION_3_2);
+        let options: Vec<&str> = options.iter().map(|option| option.as_str()).collect();
+        Some(messages::negotiate_protocol_version(
+            self.protocol_version & 0xFFFF,
+            &options,
+        ))
+    }
+
+    // The length of the secret key in BackendKeyData and CancelRequest.
+    pub fn secret_key_length(&self) -> usize {
+        if self.protocol_version >= PROTOCOL_VERSION_3_2 {
+            32
+        } else {
+            4
         }
     }
 
     // Convert the startup message back to proto bytes.
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut msg = Vec::new();
-        // Reserve for size.
-        msg.extend_from_slice(&[0, 0, 0, 0]);
-
-        // Protocol version.
-        msg.extend_from_slice(&[0, 0, 0, 0]);
-        BigEndian::write_i32(&mut msg[4..8], self.protocol_version);
+        let mut msg = messages::MessageBuilder::untyped().i32(self.protocol_version);
 
         // Key/value params.
         for (key, value) in self.parameters.iter() {
-            msg.extend_from_slice(&key.as_bytes());
-            msg.push(0);
-            msg.extend_from_slice(&value.as_bytes());
-            msg.push(0);
+            msg = msg.cstr(key).cstr(value);
         }
 
         // Terminating null byte.
-        msg.push(0);
-
-        // Update size.
-        let msg_size = msg.len() as i32;
-        BigEndian::write_i32(&mut msg[0..4], msg_size);
-
-        msg
+        msg.u8(0).finish()
     }
 }
 
@@ -583,6 +1016,143 @@ mod tests {
         );
     }
 
+    // Feed a packet a byte at a time and return what the parser made of it.
+    fn parse_startup_bytewise(packet: &[u8]) -> anyhow::Result<Option<ProtoStartup>> {
+        let mut parser = ProtoParser::new();
+        for (idx, byte) in packet.iter().enumerate() {
+            let (n, startup) = parser.parse_startup(std::slice::from_ref(byte))?;
+            assert_eq!(n, 1);
+            if startup.is_some() {
+                assert_eq!(idx, packet.len() - 1, "packet completed early");
+                return Ok(startup);
+            }
+        }
+        Ok(None)
+    }
+
+    #[test]
+    fn it_can_parse_a_startup_message_a_byte_at_a_time() {
+        let packet = expected_startup_message().as_bytes();
+        assert_eq!(
+            parse_startup_bytewise(&packet).unwrap(),
+            Some(ProtoStartup::Message(expected_startup_message()))
+        );
+
+        let ssl_request = &[0, 0, 0, 8, 4, 210, 22, 47];
+        assert_eq!(
+            parse_startup_bytewise(ssl_request).unwrap(),
+            Some(ProtoStartup::SSLRequest)
+        );
+
+        let gssenc_request = &[0, 0, 0, 8, 4, 210, 22, 48];
+        assert_eq!(
+            parse_startup_bytewise(gssenc_request).unwrap(),
+            Some(ProtoStartup::GSSENCRequest)
+        );
+
+        let cancel_request = messages::cancel_request(42, &[1, 2, 3, 4]);
+        assert_eq!(
+            parse_startup_bytewise(&cancel_request).unwrap(),
+            Some(ProtoStartup::CancelRequest {
+                process_id: 42,
+                secret_key: vec![1, 2, 3, 4],
+            })
+        );
+    }
+
+    #[test]
+    fn it_only_consumes_the_startup_packet() {
+        let mut packet = [0, 0, 0, 8, 4, 210, 22, 47].to_vec();
+        packet.extend_from_slice(&expected_startup_message().as_bytes());
+
+        let mut parser = ProtoParser::new();
+        let (n, startup) = parser.parse_startup(&packet).unwrap();
+        assert_eq!(n, 8);
+        assert_eq!(startup, Some(ProtoStartup::SSLRequest));
+
+        let (n, startup) = parser.parse_startup(&packet[8..]).unwrap();
+        assert_eq!(n, packet.len() - 8);
+        assert_eq!(
+            startup,
+            Some(ProtoStartup::Message(expected_startup_message()))
+        );
+    }
+
+    #[test]
+    fn it_rejects_malformed_startup_packets() {
+        // Too short and too long.
+        assert!(parse_startup_bytewise(&[0, 0, 0, 4]).is_err());
+        assert!(parse_startup_bytewise(&[0, 1, 0, 0]).is_err());
+        assert!(parse_startup_bytewise(&[0xFF, 0xFF, 0xFF, 0xFF]).is_err());
+
+        // Unsupported protocol version.
+        let mut startup_message = expected_startup_message();
+        startup_message.protocol_version = 2 << 16;
+        assert!(parse_startup_bytewise(&startup_message.as_bytes()).is_err());
+
+        // Non UTF-8 parameters.
+        let mut packet = expected_startup_message().as_bytes();
+        packet[10] = 0xFF;
+        assert!(parse_startup_bytewise(&packet).is_err());
+
+        // A key without a value, and parameters without the final terminator.
+        let packet = &[0, 0, 0, 13, 0, 3, 0, 0, b'u', b's', b'e', b'r', 0];
+        assert!(parse_startup_bytewise(packet).is_err());
+        let packet = &[0, 0, 0, 14, 0, 3, 0, 0, b'u', 0, b'p', 0, b'x', 0];
+        assert!(parse_startup_bytewise(packet).is_err());
+
+        // An SSLRequest with a body, and cancel keys that are too short or too long.
+        assert!(parse_startup_bytewise(&[0, 0, 0, 9, 4, 210, 22, 47, 0]).is_err());
+        assert!(parse_startup_bytewise(&[0, 0, 0, 9, 4, 210, 22, 48, 0]).is_err());
+        assert!(parse_startup_bytewise(&messages::cancel_request(42, &[1, 2, 3])).is_err());
+        assert!(parse_startup_bytewise(&messages::cancel_request(42, &[1; 257])).is_err());
+    }
+
+    #[test]
+    fn it_negotiates_the_protocol_version() {
+        // Versions up to 3.2 are fine as they are.
+        let mut startup_message = expected_startup_message();
+        assert_eq!(startup_message.negotiate_protocol_version(), None);
+        assert_eq!(startup_message.secret_key_length(), 4);
+
+        startup_message.protocol_version = PROTOCOL_VERSION_3_2;
+        assert_eq!(startup_message.negotiate_protocol_version(), None);
+        assert_eq!(startup_message.secret_key_length(), 32);
+
+        // Newer minor versions are negotiated down to 3.2.
+        startup_message.protocol_version = 3 << 16 | 9;
+        assert_eq!(
+            startup_message.negotiate_protocol_version(),
+            Some(messages::negotiate_protocol_version(2, &[]))
+        );
+        assert_eq!(startup_message.protocol_version, PROTOCOL_VERSION_3_2);
+
+        // Protocol options are dropped and reported back.
+        let mut startup_message = expected_startup_message();
+        startup_message
+            .parameters
+            .insert("_pq_.something".into(), "on".into());
+        assert_eq!(
+            startup_message.negotiate_protocol_version(),
+            Some(messages::negotiate_protocol_version(0, &["_pq_.something"]))
+        );
+        assert_eq!(startup_message, expected_startup_message());
+    }
+
+    #[test]
+    fn it_can_parse_a_startup_packet_after_an_error() {
+        let mut parser = ProtoParser::new();
+        assert!(parser.parse_startup(&[0, 0, 0, 4]).is_err());
+
+        let packet = expected_startup_message().as_bytes();
+        let (n, startup) = parser.parse_startup(&packet).unwrap();
+        assert_eq!(n, packet.len());
+        assert_eq!(
+            startup,
+            Some(ProtoStartup::Message(expected_startup_message()))
+        );
+    }
+
     #[test]
     fn it_returns_empty_when_missing_data() {
         let packet = &[84, 0, 0, 0];
@@ -755,4 +1325,78 @@ mod tests {
         assert_eq!(msgs.len(), 1);
         assert_eq!(msgs[0], ProtoMessage::Message('S', 0, packet.len() - 1));
     }
+
+    #[test]
+    fn it_rejects_invalid_msg_lengths() {
+        for length in [0, 1, 2, 3, -1, -5, i32::MIN] {
+            let mut packet = vec![b'Q'];
+            packet.extend_from_slice(&length.to_be_bytes());
+            packet.extend_from_slice(b"select 1\0");
+
+            let mut msgs = VecDeque::new();
+            let mut parser = ProtoParser::new();
+            assert!(parser.parse(&packet, &mut msgs).is_err(), "{}", length);
+            assert!(msgs.is_empty());
+        }
+    }
+
+    #[test]
+    fn it_has_no_body_for_an_invalid_range() {
+        let packet = &[b'S', 0, 0, 0, 4];
+        assert_eq!(
+            ProtoMessage::Message('S', 0, 4).body(packet),
+            Some(&b""[..])
+        );
+        assert_eq!(ProtoMessage::Message('S', 0, 2).body(packet), None);
+        assert_eq!(ProtoMessage::Message('S', 0, 9).body(packet), None);
+    }
+
+    #[test]
+    fn it_can_view_complete_msgs() {
+        let mut packet = messages::error_response("ERROR", "42P01", "oops");
+        packet.extend_from_slice(&messages::ready_for_query('T'));
+
+        let mut msgs = VecDeque::new();
+        let mut parser = ProtoParser::new();
+        parser.parse(&packet, &mut msgs).unwrap();
+
+        assert_eq!(msgs[0].error_message(&packet).unwrap(), Some("oops".into()));
+        assert_eq!(msgs[1].transaction_type(&packet), Some('T'));
+        assert_eq!(
+            msgs[1].backend(&packet),
+            Some(Backend::ReadyForQuery { status: 'T' })
+        );
+        // Clients never send a 'Z'.
+        assert_eq!(
+            msgs[1].frontend(&packet),
+            Some(Frontend::Unknown('Z', &b"T"[..]))
+        );
+    }
+
+    #[test]
+    fn it_can_skip_the_rest_of_a_partial_msg() {
+        let row = messages::data_row(&[Some(&[7; 100][..])]);
+        let mut packet = row.clone();
+        packet.extend_from_slice(&messages::ready_for_query('I'));
+
+        // Parse the start of the row, skip the rest and parse what follows.
+        let mut msgs = VecDeque::new();
+        let mut parser = ProtoParser::new();
+        assert_eq!(parser.partial_remaining(), None);
+        let n = parser.parse(&packet[..20], &mut msgs).unwrap();
+        assert_eq!(n, 20);
+        assert_eq!(msgs[0], ProtoMessage::Partial('D', 0, 19));
+        assert_eq!(parser.partial_remaining(), Some(('D', row.len() - 20)));
+
+        assert!(parser.skip(row.len()).is_err());
+        parser.skip(10).unwrap();
+        assert_eq!(parser.partial_remaining(), Some(('D', row.len() - 30)));
+        parser.skip(row.len() - 30).unwrap();
+        assert_eq!(parser.partial_remaining(), None);
+
+        let mut msgs = VecDeque::new();
+        let rest = &packet[row.len()..];
+        parser.parse(rest, &mut msgs).unwrap();
+        assert_eq!(msgs[0], ProtoMessage::Message('Z', 0, 5));
+    }
 }
@@ -0,0 +1,601 @@
+use super::ProtoMessage;
+use byteorder::{BigEndian, ByteOrder};
+use std::borrow::Cow;
+
+// Typed, zero-copy views over protocol v3 message bodies. A view borrows the
+// buffer it was parsed from, so nothing is copied unless a message was split
+// across reads (see `Assembler`).
+//
+// Some message types mean different things depending on who sent them, e.g.
+// 'D' is Describe from a client and DataRow from a server, so there is one enum
+// per direction.
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Frontend<'a> {
+    Query {
+        query: &'a str,
+    },
+    Parse {
+        name: &'a str,
+        query: &'a str,
+        param_types: Vec<i32>,
+    },
+    Bind {
+        portal: &'a str,
+        statement: &'a str,
+        param_formats: Vec<i16>,
+        params: Vec<Option<&'a [u8]>>,
+        result_formats: Vec<i16>,
+    },
+    // `kind` is 'S' for a prepared statement or 'P' for a portal.
+    Describe {
+        kind: char,
+        name: &'a str,
+    },
+    Execute {
+        portal: &'a str,
+        max_rows: i32,
+    },
+    Close {
+        kind: char,
+        name: &'a str,
+    },
+    Sync,
+    Flush,
+    Terminate,
+    CopyData(&'a [u8]),
+    CopyDone,
+    CopyFail {
+        message: &'a str,
+    },
+    FunctionCall {
+        oid: i32,
+        arg_formats: Vec<i16>,
+        args: Vec<Option<&'a [u8]>>,
+        result_format: i16,
+    },
+    // PasswordMessage, SASLInitialResponse, SASLResponse and GSSResponse all use
+    // 'p'. Which one it is depends on what the server asked for.
+    Password(&'a [u8]),
+    Unknown(char, &'a [u8]),
+}
+
+impl<'a> Frontend<'a> {
+    pub fn parse(msg_type: char, body: &'a [u8]) -> anyhow::Result<Self> {
+        let mut reader = Reader::new(body);
+        let view = match msg_type {
+            'Q' => Frontend::Query {
+                query: reader.cstr()?,
+            },
+            'P' => Frontend::Parse {
+                name: reader.cstr()?,
+                query: reader.cstr()?,
+                param_types: reader.list(|reader| reader.i32())?,
+            },
+            'B' => Frontend::Bind {
+                portal: reader.cstr()?,
+                statement: reader.cstr()?,
+                param_formats: reader.list(|reader| reader.i16())?,
+                params: reader.list(|reader| reader.value())?,
+                result_formats: reader.list(|reader| reader.i16())?,
+            },
+            'D' => Frontend::Describe {
+                kind: reader.u8()? as char,
+                name: reader.cstr()?,
+            },
+            'E' => Frontend::Execute {
+                portal: reader.cstr()?,
+                max_rows: reader.i32()?,
+            },
+            'C' => Frontend::Close {
+                kind: reader.u8()? as char,
+                name: reader.cstr()?,
+            },
+            'S' => Frontend::Sync,
+            'H' => Frontend::Flush,
+            'X' => Frontend::Terminate,
+            'd' => Frontend::CopyData(reader.rest()),
+            'c' => Frontend::CopyDone,
+            'f' => Frontend::CopyFail {
+                message: reader.cstr()?,
+            },
+            'F' => Frontend::FunctionCall {
+                oid: reader.i32()?,
+                arg_formats: reader.list(|reader| reader.i16())?,
+                args: reader.list(|reader| reader.value())?,
+                result_format: reader.i16()?,
+            },
+            'p' => Frontend::Password(reader.rest()),
+            msg_type => Frontend::Unknown(msg_type, reader.rest()),
+        };
+        Ok(view)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FieldDescription<'a> {
+    pub name: &'a str,
+    pub table_oid: i32,
+    pub column: i16,
+    pub type_oid: i32,
+    pub type_size: i16,
+    pub type_modifier: i32,
+    pub format: i16,
+}
+
+// The fields of an ErrorResponse or NoticeResponse, keyed by field type.
+#[derive(Debug, PartialEq, Clone)]
+pub struct NoticeFields<'a>(pub Vec<(char, &'a str)>);
+
+impl<'a> NoticeFields<'a> {
+    pub fn get(&self, field_type: char) -> Option<&'a str> {
+        self.0
+            .iter()
+            .find(|(key, _)| *key == field_type)
+            .map(|(_, value)| *value)
+    }
+
+    // The non-localized severity when the server sent one.
+    pub fn severity(&self) -> Option<&'a str> {
+        self.get('V').or_else(|| self.get('S'))
+    }
+
+    pub fn code(&self) -> Option<&'a str> {
+        self.get('C')
+    }
+
+    pub fn message(&self) -> Option<&'a str> {
+        self.get('M')
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Backend<'a> {
+    // `code` is 0 for AuthenticationOk, 5 for MD5, 10 for SASL, etc. `data`
+    // is whatever follows, e.g. the MD5 salt or the SASL mechanisms.
+    Authentication {
+        code: i32,
+        data: &'a [u8],
+    },
+    // The secret key is 4 bytes in protocol 3.0 and up to 256 bytes in 3.2.
+    BackendKeyData {
+        process_id: i32,
+        secret_key: &'a [u8],
+    },
+    BindComplete,
+    CloseComplete,
+    CommandComplete {
+        tag: &'a str,
+    },
+    CopyData(&'a [u8]),
+    CopyDone,
+    CopyInResponse {
+        format: i8,
+        column_formats: Vec<i16>,
+    },
+    CopyOutResponse {
+        format: i8,
+        column_formats: Vec<i16>,
+    },
+    CopyBothResponse {
+        format: i8,
+        column_formats: Vec<i16>,
+    },
+    DataRow {
+        values: Vec<Option<&'a [u8]>>,
+    },
+    EmptyQueryResponse,
+    ErrorResponse(NoticeFields<'a>),
+    FunctionCallResponse {
+        value: Option<&'a [u8]>,
+    },
+    NegotiateProtocolVersion {
+        minor: i32,
+        options: Vec<&'a str>,
+    },
+    NoData,
+    NoticeResponse(NoticeFields<'a>),
+    NotificationResponse {
+        process_id: i32,
+        channel: &'a str,
+        payload: &'a str,
+    },
+    ParameterDescription {
+        types: Vec<i32>,
+    },
+    ParameterStatus {
+        name: &'a str,
+        value: &'a str,
+    },
+    ParseComplete,
+    PortalSuspended,
+    ReadyForQuery {
+        status: char,
+    },
+    RowDescription {
+        fields: Vec<FieldDescription<'a>>,
+    },
+    Unknown(char, &'a [u8]),
+}
+
+impl<'a> Backend<'a> {
+    pub fn parse(msg_type: char, body: &'a [u8]) -> anyhow::Result<Self> {
+        let mut reader = Reader::new(body);
+        let view = match msg_type {
+            'R' => Backend::Authentication {
+                code: reader.i32()?,
+                data: reader.rest(),
+            },
+            'K' => Backend::BackendKeyData {
+                process_id: reader.i32()?,
+                secret_key: reader.rest(),
+            },
+            '2' => Backend::BindComplete,
+            '3' => Backend::CloseComplete,
+            'C' => Backend::CommandComplete {
+                tag: reader.cstr()?,
+            },
+            'd' => Backend::CopyData(reader.rest()),
+            'c' => Backend::CopyDone,
+            'G' => Backend::CopyInResponse {
+                format: reader.u8()? as i8,
+                column_formats: reader.list(|reader| reader.i16())?,
+            },
+            'H' => Backend::CopyOutResponse {
+                format: reader.u8()? as i8,
+                column_formats: reader.list(|reader| reader.i16())?,
+            },
+            'W' => Backend::CopyBothResponse {
+                format: reader.u8()? as i8,
+                column_formats: reader.list(|reader| reader.i16())?,
+            },
+            'D' => Backend::DataRow {
+                values: reader.list(|reader| reader.value())?,
+            },
+            'I' => Backend::EmptyQueryResponse,
+            'E' => Backend::ErrorResponse(reader.notice_fields()?),
+            'V' => Backend::FunctionCallResponse {
+                value: reader.value()?,
+            },
+            'v' => {
+                let minor = reader.i32()?;
+                let count = reader.i32()?;
+                let mut options = vec![];
+                for _ in 0..count {
+                    options.push(reader.cstr()?);
+                }
+                Backend::NegotiateProtocolVersion { minor, options }
+            }
+            'n' => Backend::NoData,
+            'N' => Backend::NoticeResponse(reader.notice_fields()?),
+            'A' => Backend::NotificationResponse {
+                process_id: reader.i32()?,
+                channel: reader.cstr()?,
+                payload: reader.cstr()?,
+            },
+            't' => Backend::ParameterDescription {
+                types: reader.list(|reader| reader.i32())?,
+            },
+            'S' => Backend::ParameterStatus {
+                name: reader.cstr()?,
+                value: reader.cstr()?,
+            },
+            '1' => Backend::ParseComplete,
+            's' => Backend::PortalSuspended,
+            'Z' => Backend::ReadyForQuery {
+                status: reader.u8()? as char,
+            },
+            'T' => Backend::RowDescription {
+                fields: reader.list(|reader| {
+                    Ok(FieldDescription {
+                        name: reader.cstr()?,
+                        table_oid: reader.i32()?,
+                        column: reader.i16()?,
+                        type_oid: reader.i32()?,
+                        type_size: reader.i16()?,
+                        type_modifier: reader.i32()?,
+                        format: reader.i16()?,
+                    })
+                })?,
+            },
+            msg_type => Backend::Unknown(msg_type, reader.rest()),
+        };
+        Ok(view)
+    }
+}
+
+// A cursor over a message body. Every read is bounds checked, so a malformed
+// or truncated message is an error and never a panic.
+struct Reader<'a> {
+    buffer: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buffer: &'a [u8]) -> Self {
+        Self { buffer, offset: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.buffer.len() - self.offset < len {
+            anyhow::bail!("Message is truncated");
+        }
+        let bytes = &self.buffer[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let bytes = &self.buffer[self.offset..];
+        self.offset = self.buffer.len();
+        bytes
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn i16(&mut self) -> anyhow::Result<i16> {
+        Ok(BigEndian::read_i16(self.bytes(2)?))
+    }
+
+    fn i32(&mut self) -> anyhow::Result<i32> {
+        Ok(BigEndian::read_i32(self.bytes(4)?))
+    }
+
+    fn cstr(&mut self) -> anyhow::Result<&'a str> {
+        let len = match memchr::memchr(0, &self.buffer[self.offset..]) {
+            Some(len) => len,
+            None => anyhow::bail!("Message string is missing its terminator"),
+        };
+        let bytes = self.bytes(len + 1)?;
+        Ok(std::str::from_utf8(&bytes[..len])?)
+    }
+
+    // A length prefixed value where a length of -1 means NULL.
+    fn value(&mut self) -> anyhow::Result<Option<&'a [u8]>> {
+        match self.i32()? {
+            -1 => Ok(None),
+            len if len < 0 => anyhow::bail!("Invalid value length: {}", len),
+            len => Ok(Some(self.bytes(len as usize)?)),
+        }
+    }
+
+    // An i16 count followed by that many items.
+    fn list<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> anyhow::Result<T>,
+    ) -> anyhow::Result<Vec<T>> {
+        let count = self.i16()?;
+        let mut items = Vec::with_capacity(count.max(0) as usize);
+        for _ in 0..count {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn notice_fields(&mut self) -> anyhow::Result<NoticeFields<'a>> {
+        let mut fields = vec![];
+        loop {
+            match self.u8()? {
+                0 => return Ok(NoticeFields(fields)),
+                field_type => fields.push((field_type as char, self.cstr()?)),
+            }
+        }
+    }
+}
+
+// Reassembles messages the parser handed out in pieces (a `Partial` followed by
+// more `Partial`s and a `PartialComplete`). Every message parsed from a stream
+// must be pushed, in order, so the pieces line up. Only the message types asked
+// for are returned, and only those are ever copied.
+pub struct Assembler {
+    msg_types: &'static [char],
+    max_message_size: usize,
+    pending: Option<Pending>,
+}
+
+struct Pending {
+    msg_type: char,
+    // None when the message isn't wanted or is too large to collect.
+    body: Option<Vec<u8>>,
+}
+
+impl Assembler {
+    // Messages larger than `max_message_size` are skipped instead of buffered.
+    pub fn new(msg_types: &'static [char], max_message_size: usize) -> Self {
+        Self {
+            msg_types,
+            max_message_size,
+            pending: None,
+        }
+    }
+
+    // Whether the last message pushed is a wanted one that is still coming in.
+    pub fn is_collecting(&self) -> bool {
+        matches!(self.pending, Some(Pending { body: Some(_), .. }))
+    }
+
+    // Returns the message type and body of a wanted message once it is complete.
+    // Complete messages borrow from `buffer`.
+    pub fn push<'a>(
+        &mut self,
+        msg: &ProtoMessage,
+        buffer: &'a [u8],
+    ) -> Option<(char, Cow<'a, [u8]>)> {
+        match *msg {
+            ProtoMessage::Message(msg_type, _, _) => {
+                self.pending = None;
+                if !self.msg_types.contains(&msg_type) {
+                    return None;
+                }
+                Some((msg_type, Cow::Borrowed(msg.body(buffer)?)))
+            }
+            ProtoMessage::Partial(msg_type, start, end) => {
+                match self.pending {
+                    // The middle of a message that is still coming in.
+                    Some(ref mut pending) => {
+                        if let Some(ref mut body) = pending.body {
+                            body.extend_from_slice(&buffer[start..=end]);
+                        }
+                    }
+                    // The first piece, which always has the whole header.
+                    None => {
+                        let size = BigEndian::read_i32(&buffer[start + 1..start + 5]) as usize;
+                        let body = if self.msg_types.contains(&msg_type)
+                            && size <= self.max_message_size
+                        {
+                            let mut body = Vec::with_capacity(size - 4);
+                            body.extend_from_slice(&buffer[start + 5..=end]);
+                            Some(body)
+                        } else {
+                            None
+                        };
+                        self.pending = Some(Pending { msg_type, body });
+                    }
+                }
+                None
+            }
+            ProtoMessage::PartialComplete(_, end) => {
+                let pending = self.pending.take()?;
+                let mut body = pending.body?;
+                body.extend_from_slice(&buffer[..=end]);
+                Some((pending.msg_type, Cow::Owned(body)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{messages, ProtoParser};
+    use std::collections::VecDeque;
+
+    #[test]
+    fn it_can_view_frontend_messages() {
+        let body = b"s1\0select $1\0\0\x01\0\0\0\x17";
+        assert_eq!(
+            Frontend::parse('P', body).unwrap(),
+            Frontend::Parse {
+                name: "s1",
+                query: "select $1",
+                param_types: vec![23],
+            }
+        );
+
+        let body = b"\0s1\0\0\0\0\x02\0\0\0\x011\xff\xff\xff\xff\0\0";
+        assert_eq!(
+            Frontend::parse('B', body).unwrap(),
+            Frontend::Bind {
+                portal: "",
+                statement: "s1",
+                param_formats: vec![],
+                params: vec![Some(&b"1"[..]), None],
+                result_formats: vec![],
+            }
+        );
+
+        assert_eq!(
+            Frontend::parse('E', b"\0\0\0\0\0").unwrap(),
+            Frontend::Execute {
+                portal: "",
+                max_rows: 0
+            }
+        );
+        assert_eq!(Frontend::parse('S', b"").unwrap(), Frontend::Sync);
+    }
+
+    #[test]
+    fn it_can_view_backend_messages() {
+        let body = b"SERROR\0VERROR\0C42P01\0Mrelation \"t\" does not exist\0\0";
+        match Backend::parse('E', body).unwrap() {
+            Backend::ErrorResponse(fields) => {
+                assert_eq!(fields.severity(), Some("ERROR"));
+                assert_eq!(fields.code(), Some("42P01"));
+                assert_eq!(fields.message(), Some("relation \"t\" does not exist"));
+            }
+            view => panic!("unexpected view: {:?}", view),
+        }
+
+        let body = b"\0\x01id\0\0\0\0\0\0\0\0\0\0\x17\0\x04\xff\xff\xff\xff\0\0";
+        assert_eq!(
+            Backend::parse('T', body).unwrap(),
+            Backend::RowDescription {
+                fields: vec![FieldDescription {
+                    name: "id",
+                    table_oid: 0,
+                    column: 0,
+                    type_oid: 23,
+                    type_size: 4,
+                    type_modifier: -1,
+                    format: 0,
+                }],
+            }
+        );
+
+        let body = b"\0\0\0\x07events\0hello\0";
+        assert_eq!(
+            Backend::parse('A', body).unwrap(),
+            Backend::NotificationResponse {
+                process_id: 7,
+                channel: "events",
+                payload: "hello",
+            }
+        );
+    }
+
+    #[test]
+    fn it_rejects_truncated_messages() {
+        assert!(Frontend::parse('P', b"s1\0select 1").is_err());
+        assert!(Backend::parse('D', b"\0\x01\0\0\0\x05ab").is_err());
+        assert!(Backend::parse('Z', b"").is_err());
+    }
+
+    #[test]
+    fn it_can_assemble_messages_across_reads() {
+        let mut stream = messages::query("select 1");
+        stream.extend_from_slice(&messages::query("select 'a long query'"));
+        stream.extend_from_slice(&[b'S', 0, 0, 0, 4]);
+
+        // Feed the stream a few bytes at a time, like small socket reads would.
+        for read_size in [5, 7, 16] {
+            let mut parser = ProtoParser::new();
+            let mut assembler = Assembler::new(&['Q'], 1024);
+            let mut queries = vec![];
+            let mut buffer = vec![];
+            for chunk in stream.chunks(read_size) {
+                buffer.extend_from_slice(chunk);
+                let mut msgs = VecDeque::new();
+                let n = parser.parse(&buffer, &mut msgs).unwrap();
+                for msg in msgs.iter() {
+                    if let Some((msg_type, body)) = assembler.push(msg, &buffer) {
+                        if let Frontend::Query { query } = Frontend::parse(msg_type, &body).unwrap()
+                        {
+                            queries.push(query.to_string());
+                        }
+                    }
+                }
+                buffer.drain(..n);
+            }
+            assert_eq!(queries, vec!["select 1", "select 'a long query'"]);
+        }
+    }
+
+    #[test]
+    fn it_skips_messages_that_are_too_large() {
+        let stream = messages::query("select 'a long query'");
+        let mut parser = ProtoParser::new();
+        let mut assembler = Assembler::new(&['Q'], 8);
+
+        let mut found = 0;
+        for chunk in stream.chunks(10) {
+            let mut msgs = VecDeque::new();
+            parser.parse(chunk, &mut msgs).unwrap();
+            for msg in msgs.iter() {
+                found += assembler.push(msg, chunk).iter().count();
+            }
+        }
+        assert_eq!(found, 0);
+    }
+}
@@ -0,0 +1,263 @@
+use byteorder::{BigEndian, ByteOrder};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// PROXY protocol support (https://www.haproxy.org/download/2.4/doc/proxy-protocol.txt).
+// Load balancers like HAProxy and the AWS NLB prepend a small header to every
+// connection that carries the original client address. Only the source address
+// is used by tusq; TLVs and the destination address are ignored.
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// A v1 header is never longer than 107 bytes, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+// Proxies send the header right away, so a slow one isn't waited on for long.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Read a v1 or v2 header from the connection. Along with the address, this
+// returns the bytes that were read past the header, which are the start of the
+// postgres startup packet. A `None` address means the proxy sent a LOCAL or
+// UNKNOWN header (e.g. a health check) and the socket peer address should be
+// used instead.
+pub async fn read_header<Conn>(conn: &mut Conn) -> anyhow::Result<(Option<SocketAddr>, Vec<u8>)>
+where
+    Conn: AsyncRead + Unpin,
+{
+    read_header_within(conn, HEADER_TIMEOUT).await
+}
+
+async fn read_header_within<Conn>(
+    conn: &mut Conn,
+    timeout: Duration,
+) -> anyhow::Result<(Option<SocketAddr>, Vec<u8>)>
+where
+    Conn: AsyncRead + Unpin,
+{
+    match tokio::time::timeout(timeout, read_any_header(conn)).await {
+        Ok(res) => res,
+        Err(_) => anyhow::bail!("Timed out waiting for the PROXY header"),
+    }
+}
+
+async fn read_any_header<Conn>(conn: &mut Conn) -> anyhow::Result<(Option<SocketAddr>, Vec<u8>)>
+where
+    Conn: AsyncRead + Unpin,
+{
+    // Both versions are at least 8 bytes long and can be told apart from those.
+    let mut header = [0; 16];
+    conn.read_exact(&mut header[..8]).await?;
+
+    if header.starts_with(V1_PREFIX) {
+        // The v1 header is a single text line, which may arrive together with the
+        // startup packet.
+        let mut line = [0; V1_MAX_LENGTH];
+        line[..8].copy_from_slice(&header[..8]);
+        let mut len = 8;
+        loop {
+            if let Some(end) = line[..len].windows(2).position(|w| w == b"\r\n") {
+                let addr = parse_v1(&line[..end + 2])?;
+                return Ok((addr, line[end + 2..len].to_vec()));
+            }
+            if len == V1_MAX_LENGTH {
+                anyhow::bail!("PROXY v1 header is too long");
+            }
+            match conn.read(&mut line[len..]).await? {
+                0 => anyhow::bail!("Connection closed in the PROXY v1 header"),
+                n => len += n,
+            }
+        }
+    }
+
+    if header[..8] == V2_SIGNATURE[..8] {
+        conn.read_exact(&mut header[8..]).await?;
+        let mut payload = vec![0; v2_payload_length(&header)?];
+        conn.read_exact(&mut payload).await?;
+        return Ok((parse_v2(&header, &payload)?, vec![]));
+    }
+
+    anyhow::bail!("Connection did not start with a PROXY protocol header")
+}
+
+// Parse a complete v1 line, e.g. "PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n".
+pub fn parse_v1(line: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+    let line = match line.strip_suffix(b"\r\n") {
+        Some(line) => std::str::from_utf8(line)?,
+        None => anyhow::bail!("PROXY v1 header is missing CRLF"),
+    };
+
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol, source, _destination, source_port, _destination_port] => {
+            let ip: IpAddr = source.parse()?;
+            match (*protocol, ip) {
+                ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => {}
+                _ => anyhow::bail!("PROXY v1 header has an invalid protocol: {}", protocol),
+            }
+            Ok(Some(SocketAddr::new(ip, source_port.parse()?)))
+        }
+        _ => anyhow::bail!("PROXY v1 header is malformed: {:?}", line),
+    }
+}
+
+// Validate the fixed 16 byte v2 header and return the length of the payload that follows.
+pub fn v2_payload_length(header: &[u8; 16]) -> anyhow::Result<usize> {
+    if header[..12] != *V2_SIGNATURE {
+        anyhow::bail!("PROXY v2 header has an invalid signature");
+    }
+    if header[12] >> 4 != 2 {
+        anyhow::bail!(
+            "PROXY v2 header has an unsupported version: {}",
+            header[12] >> 4
+        );
+    }
+    Ok(BigEndian::read_u16(&header[14..16]) as usize)
+}
+
+pub fn parse_v2(header: &[u8; 16], payload: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+    v2_payload_length(header)?;
+
+    match header[12] & 0x0F {
+        // LOCAL: the proxy opened the connection itself.
+        0x0 => return Ok(None),
+        // PROXY: the connection was relayed on behalf of a client.
+        0x1 => {}
+        cmd => anyhow::bail!("PROXY v2 header has an unsupported command: {}", cmd),
+    }
+
+    match header[13] {
+        // TCP over IPv4: src addr (4), dst addr (4), src port (2), dst port (2).
+        0x11 => {
+            if payload.len() < 12 {
+                anyhow::bail!("PROXY v2 IPv4 address block is too short");
+            }
+            let ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let port = BigEndian::read_u16(&payload[8..10]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // TCP over IPv6: src addr (16), dst addr (16), src port (2), dst port (2).
+        0x21 => {
+            if payload.len() < 36 {
+                anyhow::bail!("PROXY v2 IPv6 address block is too short");
+            }
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&payload[..16]);
+            let port = BigEndian::read_u16(&payload[32..34]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        // UNSPEC, UDP and unix sockets carry no usable client address.
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn v2_header(cmd: u8, family: u8, len: u16) -> [u8; 16] {
+        let mut header = [0; 16];
+        header[..12].copy_from_slice(V2_SIGNATURE);
+        header[12] = 0x20 | cmd;
+        header[13] = family;
+        BigEndian::write_u16(&mut header[14..16], len);
+        header
+    }
+
+    #[test]
+    fn it_can_parse_a_v1_header() {
+        let line = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 5432\r\n";
+        assert_eq!(
+            parse_v1(line).unwrap(),
+            Some("192.168.0.1:56324".parse().unwrap())
+        );
+
+        let line = b"PROXY TCP6 ::1 ::1 56324 5432\r\n";
+        assert_eq!(
+            parse_v1(line).unwrap(),
+            Some("[::1]:56324".parse().unwrap())
+        );
+
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn it_rejects_a_malformed_v1_header() {
+        assert!(parse_v1(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 5432").is_err());
+        assert!(parse_v1(b"PROXY TCP6 192.168.0.1 192.168.0.11 56324 5432\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 192.168.0.1\r\n").is_err());
+    }
+
+    #[test]
+    fn it_can_parse_a_v2_header() {
+        let header = v2_header(0x1, 0x11, 12);
+        let payload = &[10, 0, 0, 7, 10, 0, 0, 1, 0xDC, 0x04, 0x15, 0x38];
+        assert_eq!(v2_payload_length(&header).unwrap(), 12);
+        assert_eq!(
+            parse_v2(&header, payload).unwrap(),
+            Some("10.0.0.7:56324".parse().unwrap())
+        );
+
+        let header = v2_header(0x0, 0x00, 0);
+        assert_eq!(parse_v2(&header, &[]).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn it_returns_what_was_read_past_the_header() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 5432\r\n\0\0\0\x08";
+        let (addr, rest) = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"\0\0\0\x08");
+
+        let mut bytes = v2_header(0x1, 0x11, 12).to_vec();
+        bytes.extend_from_slice(&[10, 0, 0, 7, 10, 0, 0, 1, 0xDC, 0x04, 0x15, 0x38, 0, 0]);
+        let mut stream: &[u8] = &bytes;
+        let (addr, rest) = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("10.0.0.7:56324".parse().unwrap()));
+        assert!(rest.is_empty());
+        assert_eq!(stream, &[0, 0]);
+    }
+
+    #[tokio::test]
+    async fn it_reads_a_v1_header_in_pieces() {
+        let (mut conn, mut proxy) = tokio::io::duplex(1024);
+        let reader = tokio::spawn(async move { read_header(&mut conn).await.unwrap() });
+        for piece in [
+            &b"PROXY TCP4 192.168."[..],
+            b"0.1 192.168.0.11 56324 5432\r",
+            b"\n",
+        ] {
+            proxy.write_all(piece).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        let (addr, rest) = reader.await.unwrap();
+        assert_eq!(addr, Some("192.168.0.1:56324".parse().unwrap()));
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_gives_up_on_a_slow_header() {
+        let (mut conn, mut proxy) = tokio::io::duplex(1024);
+        proxy.write_all(b"PROXY TCP4 192.168.0.1").await.unwrap();
+        let res = read_header_within(&mut conn, Duration::from_millis(10)).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_a_v1_header_that_is_too_long() {
+        let mut bytes = b"PROXY TCP4 ".to_vec();
+        bytes.resize(200, b'1');
+        let mut stream: &[u8] = &bytes;
+        assert!(read_header(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_a_connection_without_a_header() {
+        let mut stream: &[u8] = &[0, 0, 0, 8, 4, 210, 22, 47];
+        assert!(read_header(&mut stream).await.is_err());
+    }
+}
@@ -0,0 +1,99 @@
+// Moves bytes from one socket to another with splice(2), through a pipe, so
+// they never get copied into user space. Linux only. This only depends on
+// tokio and libc so the benchmarks can include it as it is.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+// The default pipe capacity on Linux. Each splice moves at most this much.
+const PIPE_SIZE: usize = 64 * 1024;
+
+pub struct Pipe {
+    read: RawFd,
+    write: RawFd,
+}
+
+impl Pipe {
+    pub fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        let res = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            read: fds[0],
+            write: fds[1],
+        })
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+fn splice_once(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+// Move exactly `len` bytes from `from` to `to`. The pipe must be empty, and it
+// is empty again when this returns successfully.
+pub async fn splice_exact(
+    from: &TcpStream,
+    to: &TcpStream,
+    pipe: &Pipe,
+    len: usize,
+) -> io::Result<()> {
+    let mut remaining = len;
+    let mut in_pipe = 0;
+
+    while remaining > 0 || in_pipe > 0 {
+        // Fill the pipe from the socket, then drain it into the other socket.
+        if in_pipe == 0 {
+            from.readable().await?;
+            let chunk = std::cmp::min(remaining, PIPE_SIZE);
+            match from.try_io(Interest::READABLE, || {
+                splice_once(from.as_raw_fd(), pipe.write, chunk)
+            }) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    remaining -= n;
+                    in_pipe += n;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+        } else {
+            to.writable().await?;
+            match to.try_io(Interest::WRITABLE, || {
+                splice_once(pipe.read, to.as_raw_fd(), in_pipe)
+            }) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => in_pipe -= n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use crate::buffer::BUFFERS;
+use crate::config::UpdatableConfig;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Process wide counters. Client tasks bump these without any locking and they
+// are logged every `stats_period_ms`.
+pub struct Stats {
+    // Statements that depend on session state, see `analyzer`.
+    pub session_features_detected: AtomicU64,
+    pub session_features_rejected: AtomicU64,
+    // Clients holding on to a server because of a session feature. The gauge
+    // is the number currently pinned.
+    pub pins_total: AtomicU64,
+    pub pinned_clients: AtomicU64,
+    // CopyData bytes proxied in each direction, and COPYs cut short because the
+    // client went away.
+    pub copy_in_bytes: AtomicU64,
+    pub copy_out_bytes: AtomicU64,
+    pub copies_aborted: AtomicU64,
+    // Connection buffers that doubled because a read filled them.
+    pub buffer_grows: AtomicU64,
+    // Bytes moved from servers to clients with splice(2).
+    pub splice_bytes: AtomicU64,
+}
+
+pub static STATS: Stats = Stats {
+    session_features_detected: AtomicU64::new(0),
+    session_features_rejected: AtomicU64::new(0),
+    pins_total: AtomicU64::new(0),
+    pinned_clients: AtomicU64::new(0),
+    copy_in_bytes: AtomicU64::new(0),
+    copy_out_bytes: AtomicU64::new(0),
+    copies_aborted: AtomicU64::new(0),
+    buffer_grows: AtomicU64::new(0),
+    splice_bytes: AtomicU64::new(0),
+};
+
+impl Stats {
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            (
+                "session_features_detected",
+                self.session_features_detected.load(Ordering::Relaxed),
+            ),
+            (
+                "session_features_rejected",
+                self.session_features_rejected.load(Ordering::Relaxed),
+            ),
+            ("pins_total", self.pins_total.load(Ordering::Relaxed)),
+            (
+                "pinned_clients",
+                self.pinned_clients.load(Ordering::Relaxed),
+            ),
+            ("copy_in_bytes", self.copy_in_bytes.load(Ordering::Relaxed)),
+            (
+                "copy_out_bytes",
+                self.copy_out_bytes.load(Ordering::Relaxed),
+            ),
+            (
+                "copies_aborted",
+                self.copies_aborted.load(Ordering::Relaxed),
+            ),
+            ("buffer_grows", self.buffer_grows.load(Ordering::Relaxed)),
+            ("buffers_pooled", BUFFERS.len() as u64),
+            ("splice_bytes", self.splice_bytes.load(Ordering::Relaxed)),
+        ]
+    }
+}
+
+// Holds a gauge up by one for as long as it is alive.
+pub struct GaugeGuard(&'static AtomicU64);
+
+impl GaugeGuard {
+    pub fn new(gauge: &'static AtomicU64) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Self(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Log the counters forever. The period is read on every tick so a config reload
+// can change it. A period of 0 turns logging off.
+pub async fn log_periodically(config: UpdatableConfig) {
+    loop {
+        let period = config.get().await.stats_period_ms;
+        if period == 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+        tokio::time::sleep(Duration::from_millis(period)).await;
+
+        let line = STATS
+            .snapshot()
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join(" ");
+        log::info!("Stats: {}", line);
+    }
+}
@@ -0,0 +1,268 @@
+// Running under systemd: socket activation as in sd_listen_fds(3) and status
+// updates as in sd_notify(3). Both do nothing when systemd didn't ask for them.
+
+use crate::config::Listener;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Passed sockets start right after stdin, stdout and stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+// Whether the LISTEN_* or WATCHDOG_* variables are meant for this process.
+fn is_for_us(pid: Option<String>) -> bool {
+    match pid {
+        Some(pid) => pid.parse::<u32>().ok() == Some(std::process::id()),
+        None => false,
+    }
+}
+
+// The fds of the sockets systemd passed. This clears the LISTEN_* variables so
+// nothing tusq starts thinks the sockets are meant for it, which is only safe
+// while the process has a single thread: call it before starting the runtime.
+pub fn listen_fds() -> anyhow::Result<Vec<RawFd>> {
+    if !is_for_us(std::env::var("LISTEN_PID").ok()) {
+        return Ok(vec![]);
+    }
+    let count: RawFd = std::env::var("LISTEN_FDS")?.parse()?;
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let fds: Vec<RawFd> = (LISTEN_FDS_START..LISTEN_FDS_START + count).collect();
+    for fd in fds.iter() {
+        unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    Ok(fds)
+}
+
+// The listening sockets systemd passed, keyed by the address of the listener
+// each one is for. Sockets that match no listener are closed.
+pub fn listeners(
+    configured: &[Listener],
+    fds: Vec<RawFd>,
+) -> anyhow::Result<BTreeMap<String, std::net::TcpListener>> {
+    let mut listeners = BTreeMap::new();
+    for fd in fds {
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        // Dropping the listener closes sockets that aren't inet, e.g. a Unix
+        // ListenStream in the same socket unit.
+        let addr = match listener.local_addr() {
+            Ok(addr) => addr,
+            Err(err) => {
+                log::warn!("Closing socket from systemd that isn't TCP: {}", err);
+                continue;
+            }
+        };
+        let listener_config = configured.iter().find(|listener_config| {
+            listener_config.address.parse::<SocketAddr>().ok() == Some(addr)
+        });
+        match listener_config {
+            Some(listener_config) => {
+                listeners.insert(listener_config.address.clone(), listener);
+            }
+            None => log::warn!(
+                "Closing socket from systemd that isn't configured: {}",
+                addr
+            ),
+        }
+    }
+    Ok(listeners)
+}
+
+fn send(socket_path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    let path = socket_path.as_bytes();
+    // Paths starting with @ are in the abstract namespace.
+    if let Some(name) = path.strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+            return Ok(());
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = name;
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+    }
+    socket.send_to(state.as_bytes(), socket_path)?;
+    Ok(())
+}
+
+// Tell systemd about a change in state, e.g. "READY=1".
+pub fn notify(state: &str) {
+    if let Some(socket_path) = std::env::var_os("NOTIFY_SOCKET") {
+        if let Err(err) = send(&socket_path, state) {
+            log::warn!("Failed to notify systemd of {:?}: {:?}", state, err);
+        }
+    }
+}
+
+// A reload has to say when it started, so systemd can tell it apart from an
+// earlier one.
+pub fn notify_reloading() {
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", monotonic_usec()));
+}
+
+// CLOCK_MONOTONIC, which is what systemd expects timestamps in.
+fn monotonic_usec() -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000
+}
+
+// Shows that a loop the watchdog depends on, like a listener's accept loop, is
+// still going. The loop beats at least every HEARTBEAT_PERIOD, even when idle.
+#[derive(Clone, Default)]
+pub struct Heartbeat(Arc<AtomicU64>);
+
+pub const HEARTBEAT_PERIOD: Duration = Duration::from_secs(1);
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        let heartbeat = Self::default();
+        heartbeat.beat();
+        heartbeat
+    }
+
+    pub fn beat(&self) {
+        self.0.store(monotonic_usec(), Ordering::Relaxed);
+    }
+
+    // Whether the last beat was at most `max_age` before `now`.
+    fn is_fresh(&self, now: u64, max_age: Duration) -> bool {
+        now.saturating_sub(self.0.load(Ordering::Relaxed)) <= max_age.as_micros() as u64
+    }
+}
+
+// How often to send WATCHDOG=1: twice per WatchdogSec, as systemd suggests.
+fn watchdog_interval(pid: Option<String>, usec: Option<String>) -> Option<Duration> {
+    // WATCHDOG_PID is optional, but when it is set it has to be ours.
+    if pid.is_some() && !is_for_us(pid) {
+        return None;
+    }
+    match usec?.parse::<u64>() {
+        Ok(usec) if usec > 0 => Some(Duration::from_micros(usec / 2)),
+        _ => None,
+    }
+}
+
+// Send WATCHDOG=1 for as long as every heartbeat is fresh, so systemd restarts
+// tusq when one of them gets stuck. Never returns.
+pub async fn watchdog(heartbeats: Vec<Heartbeat>) {
+    let interval = watchdog_interval(
+        std::env::var("WATCHDOG_PID").ok(),
+        std::env::var("WATCHDOG_USEC").ok(),
+    );
+    let interval = match interval {
+        Some(interval) => interval,
+        None => return futures::future::pending().await,
+    };
+    let max_age = interval + HEARTBEAT_PERIOD;
+    loop {
+        let now = monotonic_usec();
+        if heartbeats
+            .iter()
+            .all(|heartbeat| heartbeat.is_fresh(now, max_age))
+        {
+            notify("WATCHDOG=1");
+        } else {
+            log::warn!("A listener stopped making progress, holding back the watchdog");
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_sends_notifications() {
+        let path = std::env::temp_dir().join(format!("tusq-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str(), "READY=1").unwrap();
+
+        let mut buffer = [0; 64];
+        let n = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn it_sends_notifications_to_abstract_sockets() {
+        use std::os::linux::net::SocketAddrExt;
+        let name = format!("tusq-notify-{}", std::process::id());
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let receiver = UnixDatagram::bind_addr(&addr).unwrap();
+        send(OsStr::new(&format!("@{}", name)), "STOPPING=1").unwrap();
+
+        let mut buffer = [0; 64];
+        let n = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"STOPPING=1");
+    }
+
+    #[test]
+    fn it_closes_sockets_that_arent_tcp() {
+        use std::os::unix::io::IntoRawFd;
+        let path = std::env::temp_dir().join(format!("tusq-listen-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp.local_addr().unwrap().to_string();
+        let configured = vec![Listener {
+            address: address.clone(),
+            proxy_protocol: false,
+            reuse_port: false,
+            databases: None,
+        }];
+
+        let fds = vec![unix.into_raw_fd(), tcp.into_raw_fd()];
+        let listeners = listeners(&configured, fds).unwrap();
+        assert_eq!(listeners.keys().collect::<Vec<_>>(), vec![&address]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_tells_a_stale_heartbeat() {
+        let heartbeat = Heartbeat::new();
+        assert!(heartbeat.is_fresh(monotonic_usec(), Duration::from_secs(1)));
+
+        heartbeat.0.store(5_000_000, Ordering::Relaxed);
+        assert!(heartbeat.is_fresh(5_500_000, Duration::from_secs(1)));
+        assert!(heartbeat.is_fresh(6_000_000, Duration::from_secs(1)));
+        assert!(!heartbeat.is_fresh(6_000_001, Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn it_reads_the_watchdog_interval() {
+        let pid = Some(std::process::id().to_string());
+        let usec = Some("30000000".to_string());
+        assert_eq!(
+            watchdog_interval(None, usec.clone()),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            watchdog_interval(pid, usec.clone()),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(watchdog_interval(Some("0".into()), usec), None);
+        assert_eq!(watchdog_interval(None, None), None);
+        assert_eq!(watchdog_interval(None, Some("0".into())), None);
+    }
+}
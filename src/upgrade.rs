@@ -0,0 +1,441 @@
+// Online upgrades. The running tusq process serves its listening sockets on a
+// Unix socket (`upgrade_socket`). A new process started with --upgrade connects,
+// receives the sockets with SCM_RIGHTS and starts accepting on them, while the
+// old process stops accepting and drains its clients. No connection attempt is
+// refused in between since the sockets never close.
+//
+// The connection stays open after that. Clients of the old process that are
+// between transactions are sent over it, each with the state needed to carry
+// on, so they move to the new process without reconnecting.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use tokio::net::UnixListener;
+use tokio::sync::{mpsc, oneshot};
+
+// The most fds a single message carries.
+const MAX_FDS: usize = 64;
+// Messages start with a line that says what they carry.
+const LISTENERS: &str = "listeners";
+const CLIENT: &str = "client";
+// Sent back by the new process once it has the sockets.
+const ACK: u8 = b'k';
+
+// The shutdown value that asks idle clients to move to the new process rather
+// than close.
+pub const MIGRATE_CLIENTS: &str = "migrate clients";
+
+// Everything a new process needs to take over a client between transactions.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientState {
+    // The address of the listener the client connected to.
+    pub listener: String,
+    pub client_addr: Option<SocketAddr>,
+    pub protocol_version: i32,
+    pub process_id: i32,
+    pub secret_key: Vec<u8>,
+    pub startup_parameters: BTreeMap<String, String>,
+    pub client_parameters: BTreeMap<String, String>,
+    pub server_parameters: BTreeMap<String, String>,
+}
+
+// A client handed to the task sending clients to the new process. `done` says
+// whether it was sent; the client's socket must stay open until then.
+pub struct Migration {
+    pub state: ClientState,
+    pub fd: RawFd,
+    pub done: oneshot::Sender<anyhow::Result<()>>,
+}
+
+pub type Migrations = mpsc::Sender<Migration>;
+
+// Send `payload` with `fds` attached. On the wire this is the payload length as
+// a u32, carrying the fds, followed by the payload.
+pub fn send_fds(stream: &UnixStream, payload: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    if fds.len() > MAX_FDS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many fds"));
+    }
+    let header = (payload.len() as u32).to_be_bytes();
+    let mut iov = libc::iovec {
+        iov_base: header.as_ptr() as *mut libc::c_void,
+        iov_len: header.len(),
+    };
+
+    let fds_size = std::mem::size_of_val(fds);
+    let control_size = unsafe { libc::CMSG_SPACE(fds_size as u32) } as usize;
+    // u64s keep the control buffer aligned for cmsghdr.
+    let mut control = vec![0u64; control_size.div_ceil(8)];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control_size as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_size as u32) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(cmsg) as *mut RawFd,
+                fds.len(),
+            );
+        }
+    }
+
+    let n = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut stream = stream;
+    stream.write_all(&header[n as usize..])?;
+    stream.write_all(payload)?;
+    Ok(())
+}
+
+// Receive a message sent with `send_fds`. The caller owns the fds.
+pub fn recv_fds(stream: &UnixStream) -> io::Result<(Vec<u8>, Vec<RawFd>)> {
+    let mut header = [0u8; 4];
+    let mut iov = libc::iovec {
+        iov_base: header.as_mut_ptr() as *mut libc::c_void,
+        iov_len: header.len(),
+    };
+    let control_size =
+        unsafe { libc::CMSG_SPACE((MAX_FDS * std::mem::size_of::<RawFd>()) as u32) } as usize;
+    let mut control = vec![0u64; control_size.div_ceil(8)];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control_size as _;
+
+    let n = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if n == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let mut fds = vec![];
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data_size = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for idx in 0..data_size / std::mem::size_of::<RawFd>() {
+                    fds.push(std::ptr::read_unaligned(data.add(idx)));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        for fd in fds.iter() {
+            unsafe { libc::close(*fd) };
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "fds were truncated",
+        ));
+    }
+
+    let mut stream = stream;
+    stream.read_exact(&mut header[n as usize..])?;
+    let mut payload = vec![0; u32::from_be_bytes(header) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok((payload, fds))
+}
+
+fn encode_listeners(addresses: &[String]) -> Vec<u8> {
+    let mut payload = LISTENERS.to_string();
+    for address in addresses.iter() {
+        payload.push('\n');
+        payload.push_str(address);
+    }
+    payload.into_bytes()
+}
+
+fn decode_listeners(payload: &[u8]) -> anyhow::Result<Vec<String>> {
+    let payload = std::str::from_utf8(payload)?;
+    let mut lines = payload.split('\n');
+    if lines.next() != Some(LISTENERS) {
+        anyhow::bail!("Expected listeners from the running tusq process");
+    }
+    Ok(lines.map(|line| line.to_string()).collect())
+}
+
+fn encode_client(state: &ClientState) -> anyhow::Result<Vec<u8>> {
+    Ok(format!("{}\n{}", CLIENT, toml::to_string(state)?).into_bytes())
+}
+
+fn decode_client(payload: &[u8]) -> anyhow::Result<ClientState> {
+    let payload = std::str::from_utf8(payload)?;
+    match payload.split_once('\n') {
+        Some((CLIENT, state)) => Ok(toml::from_str(state)?),
+        _ => anyhow::bail!("Expected a client from the old tusq process"),
+    }
+}
+
+// Take over the listening sockets of the running process, by address. The
+// returned stream then carries the clients that process hands over.
+pub fn receive_listeners(
+    path: &str,
+) -> anyhow::Result<(BTreeMap<String, std::net::TcpListener>, UnixStream)> {
+    let mut stream = UnixStream::connect(path)?;
+    let (payload, fds) = recv_fds(&stream)?;
+    // Own the fds right away so they are closed on errors.
+    let sockets: Vec<_> = fds
+        .into_iter()
+        .map(|fd| unsafe { std::net::TcpListener::from_raw_fd(fd) })
+        .collect();
+
+    let addresses = decode_listeners(&payload)?;
+    if addresses.len() != sockets.len() {
+        anyhow::bail!(
+            "Received {} listening sockets for {} addresses",
+            sockets.len(),
+            addresses.len()
+        );
+    }
+    stream.write_all(&[ACK])?;
+    Ok((addresses.into_iter().zip(sockets).collect(), stream))
+}
+
+// Receive the next client from the old process. None once it has handed over
+// all of them and exited.
+pub fn receive_client(
+    stream: &UnixStream,
+) -> anyhow::Result<Option<(ClientState, std::net::TcpStream)>> {
+    let (payload, fds) = match recv_fds(stream) {
+        Ok(msg) => msg,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut conns: Vec<_> = fds
+        .into_iter()
+        .map(|fd| unsafe { std::net::TcpStream::from_raw_fd(fd) })
+        .collect();
+
+    let state = decode_client(&payload)?;
+    match (conns.pop(), conns.is_empty()) {
+        (Some(conn), true) => Ok(Some((state, conn))),
+        _ => anyhow::bail!(
+            "Expected a single socket for client {:?}",
+            state.client_addr
+        ),
+    }
+}
+
+// Send clients to the new process until every client of this process is gone.
+// This blocks, so it runs on its own thread.
+pub fn migrate_clients(stream: UnixStream, mut migrations: mpsc::Receiver<Migration>) {
+    while let Some(migration) = migrations.blocking_recv() {
+        let res = encode_client(&migration.state)
+            .and_then(|payload| Ok(send_fds(&stream, &payload, &[migration.fd])?));
+        let _ = migration.done.send(res);
+    }
+}
+
+// Bind the upgrade socket, replacing the one left by a previous process.
+pub fn bind(path: &str) -> anyhow::Result<UnixListener> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    // Whoever connects gets the listening sockets, so only our user may.
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+// Whether the process on the other end runs as the same user as tusq.
+fn is_same_user(stream: &tokio::net::UnixStream) -> anyhow::Result<bool> {
+    let uid = unsafe { libc::geteuid() };
+    Ok(stream.peer_cred()?.uid() == uid)
+}
+
+// Hand the listening sockets to a new process. Returns the connection to it
+// once it has taken them; failed attempts are logged and the next one is
+// waited for.
+pub async fn serve(upgrade_listener: UnixListener, listeners: Vec<(String, RawFd)>) -> UnixStream {
+    let (addresses, fds): (Vec<_>, Vec<_>) = listeners.into_iter().unzip();
+    let payload = encode_listeners(&addresses);
+
+    loop {
+        let stream = match upgrade_listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                log::warn!("Failed to accept on the upgrade socket: {:?}", err);
+                continue;
+            }
+        };
+        match is_same_user(&stream) {
+            Ok(true) => {}
+            Ok(false) => {
+                log::warn!("Refusing upgrade from a process of another user");
+                continue;
+            }
+            Err(err) => {
+                log::warn!("Failed to check the user of an upgrade: {:?}", err);
+                continue;
+            }
+        }
+
+        let payload = payload.clone();
+        let fds = fds.clone();
+        let handoff = tokio::task::spawn_blocking(move || -> anyhow::Result<UnixStream> {
+            let mut stream = stream.into_std()?;
+            stream.set_nonblocking(false)?;
+            send_fds(&stream, &payload, &fds)?;
+            let mut ack = [0];
+            stream.read_exact(&mut ack)?;
+            if ack[0] != ACK {
+                anyhow::bail!("Unexpected reply from the new process: {:?}", ack[0]);
+            }
+            Ok(stream)
+        });
+        match handoff.await {
+            Ok(Ok(stream)) => return stream,
+            Ok(Err(err)) => log::warn!("Upgrade failed: {:?}", err),
+            Err(err) => log::warn!("Upgrade failed: {:?}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_passes_fds_over_a_unix_socket() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        send_fds(&sender, b"hello", &[listener.as_raw_fd()]).unwrap();
+        send_fds(&sender, b"no fds", &[]).unwrap();
+
+        let (payload, fds) = recv_fds(&receiver).unwrap();
+        assert_eq!(payload, b"hello");
+        assert_eq!(fds.len(), 1);
+        let received = unsafe { std::net::TcpListener::from_raw_fd(fds[0]) };
+        assert_eq!(
+            received.local_addr().unwrap(),
+            listener.local_addr().unwrap()
+        );
+
+        let (payload, fds) = recv_fds(&receiver).unwrap();
+        assert_eq!(payload, b"no fds");
+        assert!(fds.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_only_lets_our_user_connect() {
+        let path = std::env::temp_dir().join(format!("tusq-upgrade-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        let listener = bind(path).unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let client = tokio::net::UnixStream::connect(path).await.unwrap();
+        assert!(is_same_user(&client).unwrap());
+        drop(listener);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn it_encodes_listener_addresses() {
+        let addresses = vec!["127.0.0.1:8432".to_string(), "[::1]:6432".to_string()];
+        assert_eq!(
+            decode_listeners(&encode_listeners(&addresses)).unwrap(),
+            addresses
+        );
+        assert_eq!(
+            decode_listeners(&encode_listeners(&[])).unwrap(),
+            Vec::<String>::new()
+        );
+        assert!(decode_listeners(b"clients\n").is_err());
+    }
+
+    #[test]
+    fn it_encodes_client_state() {
+        let mut state = ClientState {
+            listener: "127.0.0.1:8432".into(),
+            client_addr: Some("10.0.0.1:51234".parse().unwrap()),
+            protocol_version: 196610,
+            process_id: 42,
+            secret_key: vec![0, 1, 254, 255],
+            startup_parameters: BTreeMap::new(),
+            client_parameters: BTreeMap::new(),
+            server_parameters: BTreeMap::new(),
+        };
+        state
+            .startup_parameters
+            .insert("database".into(), "my_db".into());
+        state
+            .client_parameters
+            .insert("application_name".into(), "line one\nline \"two\"".into());
+        state
+            .server_parameters
+            .insert("TimeZone".into(), "UTC".into());
+        assert_eq!(
+            decode_client(&encode_client(&state).unwrap()).unwrap(),
+            state
+        );
+
+        state.client_addr = None;
+        assert_eq!(
+            decode_client(&encode_client(&state).unwrap()).unwrap(),
+            state
+        );
+
+        assert!(decode_client(b"listeners\n").is_err());
+    }
+
+    #[test]
+    fn it_receives_clients_until_eof() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let state = ClientState {
+            listener: "127.0.0.1:8432".into(),
+            client_addr: None,
+            protocol_version: 196608,
+            process_id: 1,
+            secret_key: vec![1, 2, 3, 4],
+            startup_parameters: BTreeMap::new(),
+            client_parameters: BTreeMap::new(),
+            server_parameters: BTreeMap::new(),
+        };
+
+        let (tx, rx) = mpsc::channel(1);
+        let migrating = std::thread::spawn(move || migrate_clients(sender, rx));
+        let (done, result) = oneshot::channel();
+        tx.blocking_send(Migration {
+            state: state.clone(),
+            fd: conn.as_raw_fd(),
+            done,
+        })
+        .unwrap();
+        result.blocking_recv().unwrap().unwrap();
+        drop(tx);
+        migrating.join().unwrap();
+
+        let (received, received_conn) = receive_client(&receiver).unwrap().unwrap();
+        assert_eq!(received, state);
+        assert_eq!(
+            received_conn.local_addr().unwrap(),
+            conn.local_addr().unwrap()
+        );
+        assert!(receive_client(&receiver).unwrap().is_none());
+    }
+}